@@ -0,0 +1,230 @@
+//! Backgrounder job queue depth, failures and queue wait times per site from
+//! the REST API.
+//!
+//! Pending and in-progress jobs are counted as they are at the time of the
+//! poll. Failed and cancelled jobs are those completed since the previous
+//! poll, and the queue wait times are those of the jobs started since then,
+//! both up to the time the poll started: jobs that end while the poll runs
+//! are left to the next one. The first poll only records its time, so a
+//! restart does not report old failures again.
+
+use std::collections::BTreeMap;
+
+use serde_json::Value;
+
+use crate::datetime::{format_iso8601, parse_iso8601};
+use crate::error::CheckError;
+use crate::metric::{get_epoch_millis, Collection, Metric};
+use crate::poll::PollTracker;
+use crate::rest::RestClient;
+
+const JOBS_PATH: &str = "sites/{site_id}/jobs";
+const JOBS_POINTER: &str = "/backgroundJobs/backgroundJob";
+
+/// Percentiles of the queue wait time, with the field suffix.
+const PERCENTILES: &[(f64, &str)] = &[(50.0, "p50"), (90.0, "p90"), (99.0, "p99")];
+
+#[derive(Default)]
+struct JobCounts {
+    pending: i64,
+    in_progress: i64,
+    failed: i64,
+    cancelled: i64,
+    /// Queue wait of the jobs started since the previous poll, in seconds.
+    queue_waits: Vec<f64>,
+}
+
+impl JobCounts {
+    fn add(&mut self, other: &JobCounts) {
+        self.pending += other.pending;
+        self.in_progress += other.in_progress;
+        self.failed += other.failed;
+        self.cancelled += other.cancelled;
+        self.queue_waits.extend(&other.queue_waits);
+    }
+
+    fn to_metric(&self, site: &str, job_type: &str) -> Metric {
+        let mut metric = Metric::new("tableau_backgrounder")
+            .tag("site", site)
+            .tag("job_type", job_type)
+            .field("pending", self.pending)
+            .field("in_progress", self.in_progress)
+            .field("failed", self.failed)
+            .field("cancelled", self.cancelled)
+            .field("started", self.queue_waits.len() as i64);
+
+        let mut waits = self.queue_waits.clone();
+        waits.sort_by(|a, b| a.partial_cmp(b).expect("queue waits are finite"));
+        if let Some(max) = waits.last() {
+            for (percentile, suffix) in PERCENTILES {
+                metric = metric.field(&std::format!("queue_wait_{}", suffix), get_percentile(&waits, *percentile));
+            }
+            metric = metric.field("queue_wait_max", *max);
+        }
+
+        metric
+    }
+}
+
+/// Nearest-rank percentile of sorted, non-empty values.
+fn get_percentile(sorted: &[f64], percentile: f64) -> f64 {
+    let rank = (percentile / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn get_str<'a>(job: &'a Value, name: &str) -> &'a str {
+    job.get(name).and_then(Value::as_str).unwrap_or("")
+}
+
+fn get_time(job: &Value, name: &str) -> Option<i64> {
+    job.get(name).and_then(Value::as_str).and_then(parse_iso8601)
+}
+
+/// The time `name` of the job, if it is after `since` and not after `now`.
+fn get_time_between(job: &Value, name: &str, since: i64, now: i64) -> Option<i64> {
+    get_time(job, name).filter(|time| *time > since && *time <= now)
+}
+
+/// Seconds between creation and start of a job started between the polls.
+fn get_queue_wait(job: &Value, since: i64, now: i64) -> Option<f64> {
+    let started_at = get_time_between(job, "startedAt", since, now)?;
+    let created_at = get_time(job, "createdAt")?;
+    Some((started_at - created_at).max(0) as f64 / 1000.0)
+}
+
+fn get_job_type(job: &Value) -> String {
+    match get_str(job, "jobType") {
+        "" => "unknown".to_string(),
+        job_type => job_type.to_string(),
+    }
+}
+
+/// Counts the jobs of the site the session is signed in to, by job type.
+fn get_site_counts(rest: &RestClient, since: Option<u64>, now: u64) -> Result<BTreeMap<String, JobCounts>, CheckError> {
+    let mut counts: BTreeMap<String, JobCounts> = BTreeMap::new();
+
+    for job in rest.get_all_pages(&std::format!("{}?filter=status:eq:Pending", JOBS_PATH), JOBS_POINTER)? {
+        counts.entry(get_job_type(&job)).or_default().pending += 1;
+    }
+
+    for job in rest.get_all_pages(&std::format!("{}?filter=status:eq:InProgress", JOBS_PATH), JOBS_POINTER)? {
+        let entry = counts.entry(get_job_type(&job)).or_default();
+        entry.in_progress += 1;
+        if let Some(wait) = since.and_then(|since| get_queue_wait(&job, since as i64, now as i64)) {
+            entry.queue_waits.push(wait);
+        }
+    }
+
+    if let Some(since) = since {
+        let path = std::format!("{}?filter=completedAt:gt:{}", JOBS_PATH, format_iso8601(since as i64));
+        for job in rest.get_all_pages(&path, JOBS_POINTER)? {
+            let entry = counts.entry(get_job_type(&job)).or_default();
+            if get_time_between(&job, "completedAt", since as i64, now as i64).is_some() {
+                match get_str(&job, "status") {
+                    "Failed" => entry.failed += 1,
+                    "Cancelled" => entry.cancelled += 1,
+                    _ => {}
+                }
+            }
+            if let Some(wait) = get_queue_wait(&job, since as i64, now as i64) {
+                entry.queue_waits.push(wait);
+            }
+        }
+    }
+
+    Ok(counts)
+}
+
+/// Reports the jobs of every active site by job type, plus the totals of the
+/// server. Suspended sites run no jobs and cannot be switched to.
+pub fn check_backgrounder(rest: &RestClient, tracker: &PollTracker, cluster: &str,
+                          collection: &mut Collection) -> Result<(), CheckError> {
+    let now = get_epoch_millis();
+    let since = tracker.get(cluster);
+    let sites = rest.get_sites()?;
+
+    let mut total = JobCounts::default();
+    let mut details = Vec::new();
+
+    for site in sites.iter().filter(|site| site.state == "Active") {
+        rest.switch_site(&site.content_url)?;
+        let counts = get_site_counts(rest, since, now)?;
+
+        for (job_type, job_counts) in &counts {
+            collection.push(job_counts.to_metric(&site.name, job_type));
+            total.add(job_counts);
+
+            details.push(serde_json::json!({
+                "site": site.name,
+                "jobType": job_type,
+                "pending": job_counts.pending,
+                "inProgress": job_counts.in_progress,
+                "failed": job_counts.failed,
+                "cancelled": job_counts.cancelled,
+                "started": job_counts.queue_waits.len(),
+            }));
+        }
+    }
+
+    collection.push(total.to_metric("all", "all")
+        .field("status_code", 0i64)
+        .field("status", "Available"));
+
+    collection.set_detail("backgrounder", Value::from(details));
+    tracker.set(cluster, now);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2021-01-01T00:00:00Z
+    const MIDNIGHT: i64 = 1_609_459_200_000;
+
+    fn get_job(created_at: &str, started_at: &str) -> Value {
+        serde_json::json!({
+            "id": "1",
+            "jobType": "refresh_extracts",
+            "createdAt": created_at,
+            "startedAt": started_at,
+        })
+    }
+
+    #[test]
+    fn percentiles_use_the_nearest_rank() {
+        let sorted = [1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0];
+        assert_eq!(get_percentile(&sorted, 50.0), 5.0);
+        assert_eq!(get_percentile(&sorted, 95.0), 10.0);
+        assert_eq!(get_percentile(&sorted, 0.0), 1.0);
+        assert_eq!(get_percentile(&sorted, 100.0), 10.0);
+        assert_eq!(get_percentile(&[4.0], 50.0), 4.0);
+    }
+
+    #[test]
+    fn times_between_exclude_since_and_include_now() {
+        let job = get_job("2021-01-01T00:00:00Z", "2021-01-01T00:01:00Z");
+        let started_at = MIDNIGHT + 60_000;
+
+        assert_eq!(get_time_between(&job, "startedAt", started_at - 1, started_at), Some(started_at));
+        assert_eq!(get_time_between(&job, "startedAt", started_at, started_at + 1), None);
+        assert_eq!(get_time_between(&job, "startedAt", started_at - 2, started_at - 1), None);
+        assert_eq!(get_time_between(&job, "endedAt", started_at - 1, started_at), None);
+    }
+
+    #[test]
+    fn queue_wait_counts_jobs_started_since_the_last_poll() {
+        let job = get_job("2021-01-01T00:00:00Z", "2021-01-01T00:01:30.500Z");
+        let started_at = MIDNIGHT + 90_500;
+
+        assert_eq!(get_queue_wait(&job, started_at - 1, started_at), Some(90.5));
+        // started before the last poll, so already counted
+        assert_eq!(get_queue_wait(&job, started_at, started_at + 1000), None);
+        // the clocks disagree: the job started before it was created
+        assert_eq!(get_queue_wait(&get_job("2021-01-01T00:02:00Z", "2021-01-01T00:01:30.500Z"),
+                                  started_at - 1, started_at), Some(0.0));
+        assert_eq!(get_queue_wait(&serde_json::json!({ "startedAt": "2021-01-01T00:01:30.500Z" }),
+                                  started_at - 1, started_at), None);
+    }
+}
@@ -0,0 +1,111 @@
+//! Backup freshness derived from the TSM async job history.
+
+use std::time::Duration;
+
+use crate::error::CheckError;
+use crate::jobs::{get_async_jobs, AsyncJob};
+use crate::metric::{get_epoch_millis, Collection, Metric};
+use crate::tsm::TsmClient;
+
+fn is_backup_job(job: &AsyncJob) -> bool {
+    job.job_type.contains("Backup")
+}
+
+/// The newest successful backup job, if any is left in the job history.
+fn get_last_success(jobs: &[AsyncJob]) -> Option<&AsyncJob> {
+    jobs.iter()
+        .filter(|j| is_backup_job(j) && j.status == "Succeeded")
+        .filter(|j| j.finished_at().is_some())
+        .max_by_key(|j| j.finished_at())
+}
+
+/// Status code, status and age in seconds of the last successful backup.
+fn get_backup_status(last_success: Option<&AsyncJob>, max_age: Duration, now: u64) -> (i64, &'static str, Option<u64>) {
+    match last_success.and_then(AsyncJob::finished_at) {
+        Some(finished_at) => {
+            let age = now.saturating_sub(finished_at) / 1000;
+            if age > max_age.as_secs() { (2, "Stale", Some(age)) } else { (0, "Fresh", Some(age)) }
+        }
+        None => (2, "Missing", None),
+    }
+}
+
+pub fn check_backup(tsm: &TsmClient, max_age: Duration, collection: &mut Collection) -> Result<(), CheckError> {
+    let jobs = get_async_jobs(tsm)?;
+    let now = get_epoch_millis();
+
+    let last_success = get_last_success(&jobs);
+    let running = jobs.iter()
+        .filter(|j| is_backup_job(j) && j.is_running())
+        .count() as i64;
+
+    let (status_code, status, age) = get_backup_status(last_success, max_age, now);
+    let mut metric = Metric::new("tableau_backup")
+        .field("status_code", status_code)
+        .field("status", status);
+    let mut detail = serde_json::json!({ "running": running });
+
+    if let (Some(job), Some(age)) = (last_success, age) {
+        let finished_at = job.finished_at().expect("the age is that of a finished job");
+        metric = metric
+            .field("last_success", finished_at / 1000)
+            .field("age", age);
+        if let Some(created_at) = job.created_at {
+            metric = metric.field("duration", finished_at.saturating_sub(created_at) / 1000);
+        }
+        detail["lastSuccess"] = serde_json::to_value(job)?;
+    }
+
+    collection.push(metric.field("running", running));
+    collection.set_detail("backup", detail);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_600_000_000_000;
+    const HOUR: u64 = 3_600_000;
+
+    fn job(id: &str, job_type: &str, status: &str, completed_at: Option<u64>) -> AsyncJob {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "jobType": job_type,
+            "status": status,
+            "createdAt": completed_at.map(|t| t - HOUR),
+            "completedAt": completed_at,
+        })).unwrap()
+    }
+
+    #[test]
+    fn newest_successful_backup_is_chosen() {
+        let jobs = vec![
+            job("1", "GenerateBackupJob", "Succeeded", Some(NOW - 48 * HOUR)),
+            job("2", "GenerateBackupJob", "Succeeded", Some(NOW - 2 * HOUR)),
+            job("3", "GenerateBackupJob", "Failed", Some(NOW - HOUR)),
+            job("4", "ZiplogsJob", "Succeeded", Some(NOW)),
+            job("5", "GenerateBackupJob", "Running", None),
+        ];
+
+        assert_eq!(get_last_success(&jobs).map(|j| j.id.as_str()), Some("2"));
+    }
+
+    #[test]
+    fn backup_status_follows_the_age() {
+        let max_age = Duration::from_secs(24 * 3600);
+        let fresh = job("1", "GenerateBackupJob", "Succeeded", Some(NOW - 2 * HOUR));
+        let stale = job("2", "GenerateBackupJob", "Succeeded", Some(NOW - 25 * HOUR));
+
+        assert_eq!(get_backup_status(Some(&fresh), max_age, NOW), (0, "Fresh", Some(7200)));
+        assert_eq!(get_backup_status(Some(&stale), max_age, NOW), (2, "Stale", Some(90_000)));
+        assert_eq!(get_backup_status(None, max_age, NOW), (2, "Missing", None));
+    }
+
+    #[test]
+    fn missing_without_successful_backup() {
+        let jobs = vec![job("1", "GenerateBackupJob", "Failed", Some(NOW))];
+        assert!(get_last_success(&jobs).is_none());
+    }
+}
@@ -0,0 +1,215 @@
+//! Connection settings of the monitored Tableau Server clusters.
+
+use std::error::Error;
+
+use clap::ArgMatches;
+use serde::Deserialize;
+
+const DEFAULT_TSM_HOSTNAME: &str = "https://localhost:8850/";
+const DEFAULT_SYSTEMINFO_HOSTNAME: &str = "https://localhost/";
+const DEFAULT_TSM_SOCKET: &str = "/var/run/tableau/tab-controller-login-8850";
+const DEFAULT_REPOSITORY_PORT: u16 = 8060;
+const DEFAULT_REPOSITORY_DATABASE: &str = "workgroup";
+const DEFAULT_REPOSITORY_USER: &str = "readonly";
+
+fn default_tsm_hostname() -> String {
+    DEFAULT_TSM_HOSTNAME.to_string()
+}
+
+fn default_systeminfo_hostname() -> String {
+    DEFAULT_SYSTEMINFO_HOSTNAME.to_string()
+}
+
+fn default_tsm_socket() -> String {
+    DEFAULT_TSM_SOCKET.to_string()
+}
+
+fn default_repository_port() -> u16 {
+    DEFAULT_REPOSITORY_PORT
+}
+
+fn default_repository_database() -> String {
+    DEFAULT_REPOSITORY_DATABASE.to_string()
+}
+
+fn default_repository_user() -> String {
+    DEFAULT_REPOSITORY_USER.to_string()
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Cluster {
+    /// Value of the `cluster` tag, omitted when `None`.
+    pub name: Option<String>,
+    #[serde(default = "default_tsm_hostname")]
+    pub tsm_hostname: String,
+    #[serde(default = "default_systeminfo_hostname")]
+    pub systeminfo_hostname: String,
+    pub tsm_user: Option<String>,
+    pub tsm_password: Option<String>,
+    /// Name of the environment variable holding the TSM password, so the
+    /// clusters file does not need to contain it.
+    pub tsm_password_env: Option<String>,
+    #[serde(default)]
+    pub passwordless: bool,
+    #[serde(default = "default_tsm_socket")]
+    pub tsm_socket: String,
+    /// Personal Access Token of the REST API checks.
+    pub pat_name: Option<String>,
+    pub pat_secret: Option<String>,
+    pub pat_secret_env: Option<String>,
+    /// File holding the token secret, read at every sign-in so the secret
+    /// can be rotated without a restart.
+    pub pat_secret_file: Option<String>,
+    /// Content URL of the site to sign in to, empty for the default site.
+    #[serde(default)]
+    pub site: String,
+    /// Host of the repository PostgreSQL, the repository checks are
+    /// skipped when `None`.
+    pub repository_host: Option<String>,
+    #[serde(default = "default_repository_port")]
+    pub repository_port: u16,
+    #[serde(default = "default_repository_database")]
+    pub repository_database: String,
+    #[serde(default = "default_repository_user")]
+    pub repository_user: String,
+    pub repository_password: Option<String>,
+    pub repository_password_env: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ClustersFile {
+    cluster: Vec<Cluster>,
+}
+
+impl Cluster {
+    pub fn from_args(args: &ArgMatches) -> Self {
+        Cluster {
+            name: args.value_of("cluster_name").map(str::to_string),
+            tsm_hostname: args.value_of("tsm_hostname").expect("tsm_hostname must be defined").to_string(),
+            systeminfo_hostname: args.value_of("systeminfo_hostname").expect("Missing Server hostname").to_string(),
+            tsm_user: args.value_of("tsm_user").map(str::to_string),
+            tsm_password: args.value_of("tsm_password").map(str::to_string),
+            tsm_password_env: None,
+            passwordless: args.is_present("passwordless"),
+            tsm_socket: args.value_of("tsm_socket").unwrap_or(DEFAULT_TSM_SOCKET).to_string(),
+            pat_name: args.value_of("pat_name").map(str::to_string),
+            pat_secret: args.value_of("pat_secret").map(str::to_string),
+            pat_secret_env: None,
+            pat_secret_file: args.value_of("pat_secret_file").map(str::to_string),
+            site: args.value_of("site").unwrap_or("").to_string(),
+            repository_host: args.value_of("repository_host").map(str::to_string),
+            repository_port: args.value_of_t("repository_port").unwrap_or_else(|e| e.exit()),
+            repository_database: DEFAULT_REPOSITORY_DATABASE.to_string(),
+            repository_user: args.value_of("repository_user").unwrap_or(DEFAULT_REPOSITORY_USER).to_string(),
+            repository_password: args.value_of("repository_password").map(str::to_string),
+            repository_password_env: None,
+        }
+    }
+
+    /// Reads the `[[cluster]]` tables of a TOML file. Every cluster must be named.
+    pub fn from_file(path: &str) -> Result<Vec<Self>, Box<dyn Error>> {
+        let file: ClustersFile = toml::from_str(&std::fs::read_to_string(path)?)?;
+
+        let mut names = Vec::new();
+        for cluster in &file.cluster {
+            match &cluster.name {
+                None => return Err(std::format!("{}: every cluster must have a name", path).into()),
+                Some(name) if names.contains(&name) =>
+                    return Err(std::format!("{}: duplicate cluster name {}", path, name).into()),
+                Some(name) => names.push(name),
+            }
+        }
+
+        Ok(file.cluster)
+    }
+
+    pub fn get_tsm_password(&self) -> Option<String> {
+        match &self.tsm_password_env {
+            Some(var) => std::env::var(var).ok(),
+            None => self.tsm_password.clone(),
+        }
+    }
+
+    pub fn get_repository_password(&self) -> Option<String> {
+        match &self.repository_password_env {
+            Some(var) => std::env::var(var).ok(),
+            None => self.repository_password.clone(),
+        }
+    }
+
+    pub fn get_pat_secret(&self) -> std::io::Result<Option<String>> {
+        if let Some(path) = &self.pat_secret_file {
+            return Ok(Some(std::fs::read_to_string(path)?.trim().to_string()));
+        }
+
+        Ok(match &self.pat_secret_env {
+            Some(var) => std::env::var(var).ok(),
+            None => self.pat_secret.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_clusters_file(name: &str, contents: &str) -> String {
+        let path = std::env::temp_dir().join(std::format!("tme-{}-{}.toml", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn clusters_file_is_read_with_defaults() {
+        let path = write_clusters_file("valid", r#"
+            [[cluster]]
+            name = "prod"
+            tsm_hostname = "https://prod:8850/"
+
+            [[cluster]]
+            name = "test"
+            site = "finance"
+        "#);
+        let clusters = Cluster::from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(clusters.len(), 2);
+        assert_eq!(clusters[0].name.as_deref(), Some("prod"));
+        assert_eq!(clusters[0].tsm_hostname, "https://prod:8850/");
+        assert_eq!(clusters[0].systeminfo_hostname, DEFAULT_SYSTEMINFO_HOSTNAME);
+        assert_eq!(clusters[1].tsm_hostname, DEFAULT_TSM_HOSTNAME);
+        assert_eq!(clusters[1].site, "finance");
+        assert_eq!(clusters[1].repository_port, DEFAULT_REPOSITORY_PORT);
+    }
+
+    #[test]
+    fn clusters_must_have_a_name() {
+        let path = write_clusters_file("unnamed", r#"
+            [[cluster]]
+            name = "prod"
+
+            [[cluster]]
+            tsm_hostname = "https://test:8850/"
+        "#);
+        let e = Cluster::from_file(&path).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(e.to_string(), std::format!("{}: every cluster must have a name", path));
+    }
+
+    #[test]
+    fn cluster_names_must_be_unique() {
+        let path = write_clusters_file("duplicate", r#"
+            [[cluster]]
+            name = "prod"
+
+            [[cluster]]
+            name = "prod"
+            tsm_hostname = "https://test:8850/"
+        "#);
+        let e = Cluster::from_file(&path).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(e.to_string(), std::format!("{}: duplicate cluster name prod", path));
+    }
+}
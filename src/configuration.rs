@@ -0,0 +1,215 @@
+//! Drift tracking of the applied TSM configuration.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use serde_json::Value;
+
+use crate::error::CheckError;
+use crate::hash::fnv1a;
+use crate::metric::{Collection, Metric};
+use crate::tsm::TsmClient;
+
+/// Parts of key names that mark secrets, whose values are never emitted.
+const SECRET_PATTERNS: &[&str] = &["password", "secret", "token", "credential", "passphrase", "privatekey", "private_key"];
+
+const MASK: &str = "****";
+
+type Configuration = BTreeMap<String, String>;
+
+fn is_secret(key: &str) -> bool {
+    let key = key.to_lowercase();
+    SECRET_PATTERNS.iter().any(|pattern| key.contains(pattern))
+}
+
+fn mask(key: &str, value: &str) -> String {
+    if is_secret(key) { MASK.to_string() } else { value.to_string() }
+}
+
+/// Remembers the configuration seen by the previous poll, per cluster.
+#[derive(Default)]
+pub struct ConfigurationTracker {
+    previous: Mutex<HashMap<String, Configuration>>,
+}
+
+impl ConfigurationTracker {
+    /// Stores the configuration of the cluster and returns the previous one,
+    /// `None` on the first poll.
+    fn update(&self, cluster: &str, configuration: Configuration) -> Option<Configuration> {
+        let mut previous = self.previous.lock().unwrap_or_else(|e| e.into_inner());
+        previous.insert(cluster.to_string(), configuration)
+    }
+}
+
+fn get_value_string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// Extracts the configuration keys from the response. A response without a
+/// list of keys is an error, so an unexpected shape is not mistaken for the
+/// configuration.
+fn get_configuration(response: &Value) -> Result<Configuration, CheckError> {
+    let root = ["configurationVersion", "configuration"].iter()
+        .find_map(|name| response.get(name))
+        .unwrap_or(response);
+
+    let keys = ["configKeys", "configurationKeys", "keys"].iter()
+        .find_map(|name| root.get(name))
+        .ok_or_else(|| CheckError::Parse("configuration response lists no keys".to_string()))?;
+
+    Ok(match keys {
+        Value::Object(map) => map.iter()
+            .map(|(key, value)| (key.clone(), get_value_string(value)))
+            .collect(),
+        Value::Array(items) => items.iter()
+            .filter_map(|item| {
+                let key = item.get("key").and_then(Value::as_str)?;
+                Some((key.to_string(), item.get("value").map(get_value_string).unwrap_or_default()))
+            })
+            .collect(),
+        _ => return Err(CheckError::Parse("configuration keys are neither an object nor a list".to_string())),
+    })
+}
+
+/// 64-bit FNV-1a hash of the masked configuration. Secret values are left
+/// out, so the hash can be shared freely.
+fn get_hash(configuration: &Configuration) -> String {
+    let hash = fnv1a(configuration.iter().flat_map(|(key, value)| {
+        let mut line = std::format!("{}={}", key, mask(key, value)).into_bytes();
+        line.push(b'\n');
+        line
+    }));
+    std::format!("{:016x}", hash)
+}
+
+struct Change<'a> {
+    key: &'a str,
+    change: &'static str,
+    previous: Option<&'a str>,
+    value: Option<&'a str>,
+}
+
+fn get_changes<'a>(previous: &'a Configuration, current: &'a Configuration) -> Vec<Change<'a>> {
+    let mut changes: Vec<Change> = current.iter()
+        .filter_map(|(key, value)| match previous.get(key) {
+            None => Some(Change { key, change: "added", previous: None, value: Some(value) }),
+            Some(old) if old != value => Some(Change { key, change: "changed", previous: Some(old), value: Some(value) }),
+            _ => None,
+        })
+        .collect();
+
+    changes.extend(previous.iter()
+        .filter(|(key, _)| !current.contains_key(*key))
+        .map(|(key, value)| Change { key, change: "removed", previous: Some(value), value: None }));
+
+    changes.sort_by_key(|c| c.key);
+    changes
+}
+
+pub fn check_configuration(tsm: &TsmClient, tracker: &ConfigurationTracker, cluster: &str,
+                           collection: &mut Collection) -> Result<(), CheckError> {
+    let response: Value = tsm.get_json("configurations/current")?;
+    let configuration = get_configuration(&response)?;
+    let hash = get_hash(&configuration);
+    let key_count = configuration.len() as i64;
+
+    let previous = tracker.update(cluster, configuration.clone());
+    let changes = previous.as_ref()
+        .map(|previous| get_changes(previous, &configuration))
+        .unwrap_or_default();
+
+    let mut detail_changes = Vec::new();
+    for change in &changes {
+        let mut metric = Metric::new("tableau_tsm_config_change")
+            .tag("key", change.key)
+            .tag("change", change.change);
+        if let Some(previous) = change.previous {
+            metric = metric.field("previous", mask(change.key, previous));
+        }
+        if let Some(value) = change.value {
+            metric = metric.field("value", mask(change.key, value));
+        }
+        collection.push(metric);
+
+        detail_changes.push(serde_json::json!({
+            "key": change.key,
+            "change": change.change,
+            "previous": change.previous.map(|v| mask(change.key, v)),
+            "value": change.value.map(|v| mask(change.key, v)),
+        }));
+    }
+
+    let (status_code, status) = if changes.is_empty() { (0i64, "Unchanged") } else { (1i64, "Changed") };
+    collection.push(Metric::new("tableau_tsm_config")
+        .field("status_code", status_code)
+        .field("status", status)
+        .field("hash", hash.as_str())
+        .field("keys", key_count)
+        .field("changes", changes.len() as i64));
+
+    collection.set_detail("configuration", serde_json::json!({
+        "hash": hash,
+        "keys": key_count,
+        "changes": detail_changes,
+    }));
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn configuration(entries: &[(&str, &str)]) -> Configuration {
+        entries.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn secrets_are_masked() {
+        assert!(is_secret("wgserver.domain.password"));
+        assert!(is_secret("Service.JMX.Secret"));
+        assert!(is_secret("ssl.privatekey.file"));
+        assert!(!is_secret("gateway.timeout"));
+
+        assert_eq!(mask("oidc.client_secret", "s3cr3t"), MASK);
+        assert_eq!(mask("gateway.timeout", "1800"), "1800");
+    }
+
+    #[test]
+    fn hash_ignores_secret_values() {
+        let first = configuration(&[("gateway.timeout", "1800"), ("wgserver.domain.password", "a")]);
+        let rotated = configuration(&[("gateway.timeout", "1800"), ("wgserver.domain.password", "b")]);
+        let changed = configuration(&[("gateway.timeout", "900"), ("wgserver.domain.password", "a")]);
+
+        assert_eq!(get_hash(&first), get_hash(&rotated));
+        assert_ne!(get_hash(&first), get_hash(&changed));
+        assert_eq!(get_hash(&first).len(), 16);
+    }
+
+    #[test]
+    fn changes_are_listed_by_key() {
+        let previous = configuration(&[("a.removed", "1"), ("b.changed", "1"), ("c.same", "1")]);
+        let current = configuration(&[("b.changed", "2"), ("c.same", "1"), ("d.added", "1")]);
+
+        let changes: Vec<_> = get_changes(&previous, &current).iter()
+            .map(|c| (c.key, c.change, c.previous, c.value))
+            .collect();
+        assert_eq!(changes, vec![
+            ("a.removed", "removed", Some("1"), None),
+            ("b.changed", "changed", Some("1"), Some("2")),
+            ("d.added", "added", None, Some("1")),
+        ]);
+    }
+
+    #[test]
+    fn configuration_requires_a_list_of_keys() {
+        let response = serde_json::json!({ "configurationVersion": { "configKeys": { "gateway.timeout": 1800 } } });
+        assert_eq!(get_configuration(&response).unwrap(), configuration(&[("gateway.timeout", "1800")]));
+
+        let response = serde_json::json!({ "configurationVersion": { "version": "4" } });
+        assert!(matches!(get_configuration(&response), Err(CheckError::Parse(_))));
+    }
+}
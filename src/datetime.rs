@@ -0,0 +1,83 @@
+//! Calendar conversions for the timestamps of the Tableau APIs, which are
+//! either epoch milliseconds or ISO 8601 strings.
+
+pub const MILLIS_PER_DAY: i64 = 86_400_000;
+
+/// Days since the epoch of a proleptic Gregorian calendar date.
+pub fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let month_index = if month > 2 { month - 3 } else { month + 9 };
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Proleptic Gregorian calendar date of a number of days since the epoch.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = if days >= 0 { days } else { days - 146_096 } / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    (if month <= 2 { era * 400 + year_of_era + 1 } else { era * 400 + year_of_era }, month, day)
+}
+
+/// Parses `YYYY-MM-DDTHH:MM:SS`, with optional fractional seconds and a `Z`
+/// or `±HH:MM` offset (UTC when missing), into epoch milliseconds.
+pub fn parse_iso8601(value: &str) -> Option<i64> {
+    let number = |range: std::ops::Range<usize>| -> Option<i64> { value.get(range)?.parse().ok() };
+
+    let date = days_from_civil(number(0..4)?, number(5..7)?, number(8..10)?);
+    let time = number(11..13)? * 3600 + number(14..16)? * 60 + number(17..19)?;
+    let mut millis = (date * 86_400 + time) * 1000;
+
+    let mut rest = value.get(19..)?;
+    if let Some(fraction) = rest.strip_prefix('.') {
+        let digits = fraction.find(|c: char| !c.is_ascii_digit()).unwrap_or(fraction.len());
+        let padded = std::format!("{:0<3}", &fraction[..digits.min(3)]);
+        millis += padded.parse::<i64>().ok()?;
+        rest = &fraction[digits..];
+    }
+
+    match rest.as_bytes().first() {
+        None | Some(b'Z') => Some(millis),
+        Some(sign @ b'+') | Some(sign @ b'-') => {
+            let offset = (rest.get(1..3)?.parse::<i64>().ok()? * 60
+                + rest.get(rest.len() - 2..)?.parse::<i64>().ok()?) * 60_000;
+            Some(if *sign == b'+' { millis - offset } else { millis + offset })
+        }
+        _ => None,
+    }
+}
+
+/// Formats epoch milliseconds as `YYYY-MM-DDTHH:MM:SSZ`.
+pub fn format_iso8601(millis: i64) -> String {
+    let seconds = millis.div_euclid(1000);
+    let (year, month, day) = civil_from_days(seconds.div_euclid(86_400));
+    let time = seconds.rem_euclid(86_400);
+    std::format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, time / 3600, time / 60 % 60, time % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn iso8601_round_trip() {
+        assert_eq!(parse_iso8601("2021-03-04T05:06:07Z"), Some(1_614_834_367_000));
+        assert_eq!(format_iso8601(1_614_834_367_000), "2021-03-04T05:06:07Z");
+        assert_eq!(format_iso8601(0), "1970-01-01T00:00:00Z");
+    }
+
+    #[test]
+    fn iso8601_fraction_and_offset() {
+        assert_eq!(parse_iso8601("2021-03-04T05:06:07.25Z"), Some(1_614_834_367_250));
+        assert_eq!(parse_iso8601("2021-03-04T06:06:07+01:00"), Some(1_614_834_367_000));
+        assert_eq!(parse_iso8601("2021-03-04"), None);
+    }
+}
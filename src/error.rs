@@ -0,0 +1,242 @@
+//! Classified errors of the checks, so an unavailable check can tell a
+//! server that is down from rejected credentials.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+#[derive(Debug, Clone)]
+pub enum CheckError {
+    /// The server actively refused the connection.
+    ConnectRefused(String),
+    /// The connection failed otherwise, e.g. name resolution or routing.
+    Connect(String),
+    Tls(String),
+    Timeout(String),
+    /// The server rejected the credentials or the session (401, 403).
+    AuthRejected(String),
+    /// The TSM controller socket of the passwordless login failed.
+    PasswordlessSocket(String),
+    HttpStatus(u16, String),
+    /// The response is not the expected JSON or XML document.
+    Parse(String),
+    Other(String),
+}
+
+impl CheckError {
+    /// Value of the `error_kind` tag.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            CheckError::ConnectRefused(_) => "connect_refused",
+            CheckError::Connect(_) => "connect_failed",
+            CheckError::Tls(_) => "tls",
+            CheckError::Timeout(_) => "timeout",
+            CheckError::AuthRejected(_) => "auth_rejected",
+            CheckError::PasswordlessSocket(_) => "passwordless_socket",
+            CheckError::HttpStatus(_, _) => "http_status",
+            CheckError::Parse(_) => "parse",
+            CheckError::Other(_) => "other",
+        }
+    }
+
+    /// HTTP status code of an unexpected response.
+    pub fn http_status(&self) -> Option<u16> {
+        match self {
+            CheckError::HttpStatus(code, _) => Some(*code),
+            _ => None,
+        }
+    }
+
+    /// Classifies an I/O error by the error it wraps and by its kind: ureq
+    /// reports TLS failures as wrapped I/O errors, and invalid JSON bodies as
+    /// invalid data without a wrapped error.
+    fn from_io(e: &io::Error, message: String) -> Option<Self> {
+        if e.get_ref().is_some_and(|inner| inner.is::<rustls::TLSError>()) {
+            return Some(CheckError::Tls(message));
+        }
+
+        match e.kind() {
+            io::ErrorKind::ConnectionRefused => Some(CheckError::ConnectRefused(message)),
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => Some(CheckError::Timeout(message)),
+            io::ErrorKind::InvalidData => Some(CheckError::Parse(message)),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for CheckError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckError::ConnectRefused(message)
+            | CheckError::Connect(message)
+            | CheckError::Tls(message)
+            | CheckError::Timeout(message)
+            | CheckError::AuthRejected(message)
+            | CheckError::PasswordlessSocket(message)
+            | CheckError::HttpStatus(_, message)
+            | CheckError::Parse(message)
+            | CheckError::Other(message) => f.write_str(message),
+        }
+    }
+}
+
+impl Error for CheckError {}
+
+impl From<ureq::Error> for CheckError {
+    fn from(e: ureq::Error) -> Self {
+        let message = e.to_string();
+        match e {
+            ureq::Error::Status(401, _) | ureq::Error::Status(403, _) => CheckError::AuthRejected(message),
+            ureq::Error::Status(code, _) => CheckError::HttpStatus(code, message),
+            ureq::Error::Transport(_) => {
+                // rustls only accepts DNS names as server names
+                if e.source().is_some_and(|source| source.is::<webpki::InvalidDNSNameError>()) {
+                    return CheckError::Tls(message);
+                }
+                let io_error = e.source().and_then(|source| source.downcast_ref::<io::Error>());
+                if let Some(classified) = io_error.and_then(|io_error| CheckError::from_io(io_error, message.clone())) {
+                    return classified;
+                }
+                match e.kind() {
+                    ureq::ErrorKind::Dns | ureq::ErrorKind::ConnectionFailed
+                    | ureq::ErrorKind::ProxyConnect => CheckError::Connect(message),
+                    _ => CheckError::Other(message),
+                }
+            }
+        }
+    }
+}
+
+impl From<io::Error> for CheckError {
+    fn from(e: io::Error) -> Self {
+        let message = e.to_string();
+        CheckError::from_io(&e, message.clone()).unwrap_or(CheckError::Other(message))
+    }
+}
+
+impl From<serde_json::Error> for CheckError {
+    fn from(e: serde_json::Error) -> Self {
+        CheckError::Parse(e.to_string())
+    }
+}
+
+impl From<roxmltree::Error> for CheckError {
+    fn from(e: roxmltree::Error) -> Self {
+        CheckError::Parse(e.to_string())
+    }
+}
+
+impl From<thrift::Error> for CheckError {
+    fn from(e: thrift::Error) -> Self {
+        CheckError::PasswordlessSocket(e.to_string())
+    }
+}
+
+impl From<postgres::Error> for CheckError {
+    fn from(e: postgres::Error) -> Self {
+        // the error itself only tells the stage, e.g. "db error"
+        let message = match e.source() {
+            Some(source) => std::format!("{}: {}", e, source),
+            None => e.to_string(),
+        };
+        if let Some(code) = e.code() {
+            if *code == postgres::error::SqlState::INVALID_PASSWORD
+                || *code == postgres::error::SqlState::INVALID_AUTHORIZATION_SPECIFICATION {
+                return CheckError::AuthRejected(message);
+            }
+        }
+        match e.source().and_then(|source| source.downcast_ref::<io::Error>()) {
+            Some(io_error) => CheckError::from_io(io_error, message.clone()).unwrap_or(CheckError::Connect(message)),
+            None => CheckError::Other(message),
+        }
+    }
+}
+
+impl From<String> for CheckError {
+    fn from(message: String) -> Self {
+        CheckError::Other(message)
+    }
+}
+
+impl From<&str> for CheckError {
+    fn from(message: &str) -> Self {
+        CheckError::Other(message.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A local port nothing listens on.
+    fn get_closed_port() -> u16 {
+        std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+    }
+
+    fn status(code: u16) -> ureq::Error {
+        ureq::Error::Status(code, ureq::Response::new(code, "Status", "").unwrap())
+    }
+
+    #[test]
+    fn http_statuses_are_classified() {
+        assert_eq!(CheckError::from(status(401)).kind(), "auth_rejected");
+        assert_eq!(CheckError::from(status(403)).kind(), "auth_rejected");
+
+        let error = CheckError::from(status(503));
+        assert_eq!(error.kind(), "http_status");
+        assert_eq!(error.http_status(), Some(503));
+    }
+
+    #[test]
+    fn refused_connection_is_classified() {
+        let e = ureq::get(&std::format!("http://127.0.0.1:{}/", get_closed_port())).call().unwrap_err();
+        assert_eq!(CheckError::from(e).kind(), "connect_refused");
+    }
+
+    #[test]
+    fn invalid_json_body_is_a_parse_error() {
+        let response = ureq::Response::new(200, "OK", "<html>").unwrap();
+        let e = response.into_json::<serde_json::Value>().unwrap_err();
+        assert_eq!(CheckError::from(e).kind(), "parse");
+    }
+
+    #[test]
+    fn wrapped_tls_error_is_not_a_parse_error() {
+        let e = io::Error::new(io::ErrorKind::InvalidData, rustls::TLSError::HandshakeNotComplete);
+        assert_eq!(CheckError::from(e).kind(), "tls");
+    }
+
+    #[test]
+    fn io_errors_are_classified_by_kind() {
+        let kind = |kind| CheckError::from(io::Error::new(kind, "test")).kind();
+        assert_eq!(kind(io::ErrorKind::ConnectionRefused), "connect_refused");
+        assert_eq!(kind(io::ErrorKind::TimedOut), "timeout");
+        assert_eq!(kind(io::ErrorKind::WouldBlock), "timeout");
+        assert_eq!(kind(io::ErrorKind::PermissionDenied), "other");
+    }
+
+    #[test]
+    fn postgres_errors_are_classified() {
+        let e = postgres::Config::new()
+            .host("127.0.0.1")
+            .port(get_closed_port())
+            .user("readonly")
+            .connect(postgres::NoTls)
+            .err()
+            .expect("nothing listens on the port");
+        let error = CheckError::from(e);
+        assert_eq!(error.kind(), "connect_refused");
+        assert!(error.to_string().contains(": "), "{}", error);
+    }
+
+    #[test]
+    #[ignore = "needs a scratch PostgreSQL database in TME_TEST_PG"]
+    fn rejected_postgres_password_is_classified() {
+        let mut db = crate::repository::test_database::open();
+        db.cluster.repository_password = Some("wrong password".to_string());
+
+        let session = crate::repository::RepositorySession::default();
+        let error = session.query(&db.cluster, |_| Ok(())).unwrap_err();
+        assert_eq!(error.kind(), "auth_rejected");
+    }
+}
@@ -0,0 +1,180 @@
+//! Extract refresh failures from the background jobs of the repository.
+
+use postgres::Client;
+
+use crate::cluster::Cluster;
+use crate::error::CheckError;
+use crate::metric::{Collection, Metric};
+use crate::poll::PollTracker;
+use crate::repository::{between_polls, get_database_now, RepositorySession};
+
+/// `finish_code` of a failed background job; 0 is success, 2 cancelled.
+const FINISH_CODE_FAILED: i32 = 1;
+
+/// Parts of the job notes of refreshes stopped by the refresh time limit.
+const TIMEOUT_PATTERNS: &[&str] = &["timed out", "timeout", "time limit"];
+
+struct RefreshFailure {
+    job_id: i64,
+    job_type: String,
+    site: String,
+    project: String,
+    object_type: &'static str,
+    object: String,
+    message: String,
+    /// Seconds between start and completion.
+    duration: Option<i64>,
+}
+
+impl RefreshFailure {
+    fn reason(&self) -> &'static str {
+        let message = self.message.to_lowercase();
+        if TIMEOUT_PATTERNS.iter().any(|pattern| message.contains(pattern)) { "timeout" } else { "failed" }
+    }
+}
+
+/// Refreshes completed with an error between the polls. The job only
+/// carries the name of the workbook or data source, which is looked up on
+/// the site for its project. Names are not unique across the projects of a
+/// site, so the project stays unknown when the objects of that name are in
+/// more than one.
+fn get_failures(client: &mut Client, since: u64, now: u64) -> Result<Vec<RefreshFailure>, CheckError> {
+    let query = std::format!(
+        "SELECT bj.id::int8, bj.job_type, coalesce(s.name, 'Unknown'), coalesce(p.name, 'Unknown'),
+                bj.subtitle = 'Workbook', coalesce(bj.title, ''), coalesce(bj.notes, ''),
+                extract(epoch FROM bj.completed_at - bj.started_at)::int8
+         FROM background_jobs bj
+         LEFT JOIN sites s ON s.id = bj.site_id
+         LEFT JOIN LATERAL (
+             SELECT min(projects.name) AS name
+             FROM (SELECT w.project_id FROM workbooks w
+                   WHERE bj.subtitle = 'Workbook' AND w.site_id = bj.site_id AND w.name = bj.title
+                   UNION ALL
+                   SELECT d.project_id FROM datasources d
+                   WHERE bj.subtitle IS DISTINCT FROM 'Workbook' AND d.site_id = bj.site_id
+                     AND d.name = bj.title) objects
+             JOIN projects ON projects.id = objects.project_id
+             HAVING count(DISTINCT objects.project_id) = 1
+         ) p ON true
+         WHERE bj.job_type IN ('RefreshExtracts', 'IncrementExtracts')
+           AND bj.finish_code = $3
+           AND {}
+         ORDER BY bj.id", between_polls("bj.completed_at"));
+
+    Ok(client.query(query.as_str(), &[&(since as i64), &(now as i64), &FINISH_CODE_FAILED])?
+        .iter()
+        .map(|row| RefreshFailure {
+            job_id: row.get(0),
+            job_type: row.get(1),
+            site: row.get(2),
+            project: row.get(3),
+            object_type: if row.get::<_, Option<bool>>(4) == Some(true) { "workbook" } else { "datasource" },
+            object: row.get(5),
+            message: row.get(6),
+            duration: row.get(7),
+        })
+        .collect())
+}
+
+/// Emits a `tableau_extract_refresh_failure` event per refresh that failed
+/// or timed out since the previous poll, and a summary whose status turns to
+/// error when there was any. The first poll only records its time.
+pub fn check_extract_refreshes(session: &RepositorySession, cluster: &Cluster, tracker: &PollTracker,
+                               collection: &mut Collection) -> Result<(), CheckError> {
+    let name = cluster.name.as_deref().unwrap_or("");
+    let since = tracker.get(name);
+
+    let (now, failures) = session.query(cluster, |client| {
+        let now = get_database_now(client)?;
+        let failures = match since {
+            Some(since) => get_failures(client, since, now)?,
+            None => Vec::new(),
+        };
+        Ok((now, failures))
+    })?;
+
+    let mut details = Vec::new();
+    for failure in &failures {
+        // the job id is a field, a tag would add a series for every job
+        let mut metric = Metric::new("tableau_extract_refresh_failure")
+            .tag("site", &failure.site)
+            .tag("project", &failure.project)
+            .tag("object_type", failure.object_type)
+            .tag("object", &failure.object)
+            .tag("reason", failure.reason())
+            .field("job_id", failure.job_id)
+            .field("job_type", failure.job_type.as_str())
+            .field("message", failure.message.as_str());
+        if let Some(duration) = failure.duration {
+            metric = metric.field("duration", duration);
+        }
+        collection.push(metric);
+
+        details.push(serde_json::json!({
+            "jobId": failure.job_id,
+            "jobType": failure.job_type,
+            "site": failure.site,
+            "project": failure.project,
+            "objectType": failure.object_type,
+            "object": failure.object,
+            "reason": failure.reason(),
+            "message": failure.message,
+            "duration": failure.duration,
+        }));
+    }
+
+    let timed_out = failures.iter().filter(|failure| failure.reason() == "timeout").count() as i64;
+    let (status_code, status) = if failures.is_empty() { (0i64, "Ok") } else { (2i64, "Failed") };
+    collection.push(Metric::new("tableau_extract_refresh")
+        .field("status_code", status_code)
+        .field("status", status)
+        .field("failed", failures.len() as i64 - timed_out)
+        .field("timed_out", timed_out));
+
+    collection.set_detail("extract_refreshes", serde_json::Value::from(details));
+    tracker.set(name, now);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::test_database;
+
+    #[test]
+    #[ignore = "needs a scratch PostgreSQL database in TME_TEST_PG"]
+    fn failures_are_read_with_their_project() {
+        let mut db = test_database::open();
+        db.client.batch_execute(
+            "INSERT INTO sites VALUES (1, 'Default'), (2, 'Finance');
+             INSERT INTO projects VALUES (1, 'Sales', 1), (2, 'Ledger', 2), (3, 'Archive', 1);
+             INSERT INTO workbooks VALUES (1, 'Revenue', 1, 1), (2, 'Revenue', 1, 3), (3, 'Forecast', 1, 1);
+             INSERT INTO datasources VALUES (1, 'GL Extract', 2, 2), (2, 'GL Extract', 2, 2);
+             INSERT INTO background_jobs (job_type, site_id, title, subtitle, finish_code, notes,
+                                          started_at, completed_at) VALUES
+                 ('RefreshExtracts', 1, 'Forecast', 'Workbook', 1, 'login failed',
+                  now() AT TIME ZONE 'UTC' - interval '50 seconds', now() AT TIME ZONE 'UTC'),
+                 ('IncrementExtracts', 2, 'GL Extract', 'Data Source', 1, 'The refresh timed out',
+                  now() AT TIME ZONE 'UTC' - interval '2 hours', now() AT TIME ZONE 'UTC'),
+                 ('RefreshExtracts', 1, 'Revenue', 'Workbook', 1, 'failed', NULL, now() AT TIME ZONE 'UTC'),
+                 ('RefreshExtracts', 2, 'GL Extract', 'Data Source', 0, NULL, NULL, now() AT TIME ZONE 'UTC'),
+                 ('RefreshExtracts', 1, 'Forecast', 'Workbook', 1, 'failed', NULL,
+                  now() AT TIME ZONE 'UTC' - interval '2 hours'),
+                 ('SingleSubscriptionNotify', 1, 'Forecast', 'Workbook', 1, 'failed', NULL,
+                  now() AT TIME ZONE 'UTC')").unwrap();
+
+        let now = get_database_now(&mut db.client).unwrap();
+        let failures = get_failures(&mut db.client, now - 3_600_000, now).unwrap();
+        let summary: Vec<_> = failures.iter()
+            .map(|f| (f.site.as_str(), f.project.as_str(), f.object_type, f.object.as_str(), f.reason()))
+            .collect();
+        assert_eq!(summary, vec![
+            ("Default", "Sales", "workbook", "Forecast", "failed"),
+            ("Finance", "Ledger", "datasource", "GL Extract", "timeout"),
+            ("Default", "Unknown", "workbook", "Revenue", "failed"),
+        ]);
+        assert_eq!(failures[0].duration, Some(50));
+        assert_eq!(failures[2].duration, None);
+    }
+}
@@ -0,0 +1,249 @@
+//! Mapping of the line protocol shaped metrics to gauges, shared by the
+//! Prometheus and OpenTelemetry outputs.
+//!
+//! The `status_code` field becomes the value of a gauge named after the
+//! measurement, labelled with the tags and the state strings. Every other
+//! numeric field becomes a `<measurement>_<field>` gauge labelled with the
+//! tags only.
+
+use crate::metric::{FieldValue, Metric};
+
+/// String fields that are exposed as labels of the status gauge. Every other
+/// string field (messages, error codes) would explode label cardinality and
+/// is left out.
+const LABEL_FIELDS: &[&str] = &["status", "deployment_state", "requested_deployment_state"];
+
+/// The field holding the value of the `<measurement>` gauge itself.
+const VALUE_FIELD: &str = "status_code";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GaugeValue {
+    Int(i64),
+    Double(f64),
+}
+
+pub struct GaugeSample {
+    pub labels: Vec<(String, String)>,
+    pub value: GaugeValue,
+    pub timestamp: u128,
+}
+
+pub struct GaugeFamily {
+    pub name: String,
+    pub samples: Vec<GaugeSample>,
+}
+
+pub fn get_help(name: &str) -> &'static str {
+    match name {
+        "tableau_tsm_status" => "TSM status code (0 running, 1 busy or passive, 2 error, 3 unavailable, -1 disabled)",
+        "tableau_tsm_status_elapsed" => "Time spent querying the TSM status API in microseconds",
+        "tableau_tsm_status_timestamp_utc" => "Time of the last TSM status change of the service instance",
+        "tableau_tsm_status_seconds_in_state" => "Seconds the service instance has been in its current status",
+        "tableau_tsm_status_transitions" => "Number of status changes of the service instance since the start of the process",
+        "tableau_tsm_transition_previous_seconds_in_state" => "Seconds the service instance spent in its previous status",
+        "tableau_systeminfo" => "systeminfo.xml status code (0 active, 1 busy or passive, 2 error, 3 unavailable)",
+        "tableau_systeminfo_elapsed" => "Time spent downloading systeminfo.xml in microseconds",
+        "tableau_tsm_jobs" => "TSM async job status code (0 ok, 2 failed or stuck job, 3 unavailable)",
+        "tableau_tsm_jobs_count" => "Number of TSM async jobs by job type and status",
+        "tableau_tsm_jobs_running" => "Number of running TSM async jobs",
+        "tableau_tsm_jobs_recent_failures" => "Number of TSM async jobs failed within the failure window",
+        "tableau_tsm_jobs_oldest_running_age" => "Age of the oldest running TSM async job in seconds",
+        "tableau_license" => "License status code (0 valid, 1 expiring soon, 2 expired or invalid, 3 unavailable)",
+        "tableau_license_days_to_expiry" => "Days until the license expires",
+        "tableau_license_maintenance_days_to_expiry" => "Days until the maintenance of the license expires",
+        "tableau_license_licensed_cores" => "Number of cores licensed",
+        "tableau_license_licensed_users" => "Number of users licensed",
+        "tableau_license_used_cores" => "Number of licensed cores in use",
+        "tableau_license_used_users" => "Number of licensed users in use",
+        "tableau_tsm_pending_changes" => "Pending configuration changes status code (0 none, 1 pending, 3 unavailable)",
+        "tableau_tsm_pending_changes_pending_keys" => "Number of configuration keys waiting to be applied",
+        "tableau_tsm_pending_changes_pending_seconds" => "Seconds since the pending change was first seen",
+        "tableau_backup" => "Backup status code (0 fresh, 2 stale or missing, 3 unavailable)",
+        "tableau_backup_last_success" => "Completion time of the last successful backup in epoch seconds",
+        "tableau_backup_age" => "Seconds since the last successful backup completed",
+        "tableau_backup_duration" => "Duration of the last successful backup in seconds",
+        "tableau_backup_running" => "Number of running backup jobs",
+        "tableau_tsm_session_logged_in" => "Whether a TSM session is open (1) or not (0)",
+        "tableau_tsm_session_logins" => "Number of TSM logins since the start of the process",
+        "tableau_tsm_session_login_failures" => "Number of failed TSM logins since the start of the process",
+        "tableau_tsm_session_login_latency" => "Duration of the last TSM login in microseconds",
+        "tableau_node_info" => "Topology status code (0 available, 3 unavailable)",
+        "tableau_node_info_nodes" => "Number of nodes in the active topology",
+        "tableau_node_info_processes" => "Number of process instances configured on the node",
+        "tableau_node_info_cores" => "Number of processor cores of the node",
+        "tableau_node_info_memory" => "Physical memory of the node in bytes",
+        "tableau_node_info_disk_total" => "Total disk space of the node in bytes",
+        "tableau_node_info_disk_free" => "Free disk space of the node in bytes",
+        "tableau_tsm_config" => "Configuration drift status code (0 unchanged, 1 changed since the last poll, 3 unavailable)",
+        "tableau_tsm_config_keys" => "Number of applied configuration keys",
+        "tableau_tsm_config_changes" => "Number of configuration keys changed since the last poll",
+        "tableau_rest_session_signed_in" => "Whether a REST API session is open (1) or not (0)",
+        "tableau_rest_session_sign_ins" => "Number of REST API sign-ins since the start of the process",
+        "tableau_rest_session_sign_in_failures" => "Number of failed REST API sign-ins since the start of the process",
+        "tableau_rest_session_sign_in_latency" => "Duration of the last REST API sign-in in microseconds",
+        "tableau_site" => "Site status code (0 active, -1 suspended, 3 unavailable)",
+        "tableau_site_sites" => "Number of sites",
+        "tableau_site_suspended" => "Number of suspended sites",
+        "tableau_backgrounder" => "Backgrounder check status code (0 available, 3 unavailable)",
+        "tableau_backgrounder_pending" => "Number of background jobs waiting in the queue",
+        "tableau_backgrounder_in_progress" => "Number of background jobs running",
+        "tableau_backgrounder_failed" => "Number of background jobs failed since the last poll",
+        "tableau_backgrounder_cancelled" => "Number of background jobs cancelled since the last poll",
+        "tableau_backgrounder_started" => "Number of background jobs started since the last poll",
+        "tableau_backgrounder_queue_wait_p50" => "Median queue wait of the jobs started since the last poll in seconds",
+        "tableau_backgrounder_queue_wait_p90" => "90th percentile queue wait of the jobs started since the last poll in seconds",
+        "tableau_backgrounder_queue_wait_p99" => "99th percentile queue wait of the jobs started since the last poll in seconds",
+        "tableau_backgrounder_queue_wait_max" => "Longest queue wait of the jobs started since the last poll in seconds",
+        "tableau_repository" => "Repository status code (0 available, 1 long running background job, 3 unavailable)",
+        "tableau_repository_sessions" => "Number of sessions in the repository",
+        "tableau_repository_database_size" => "Size of the repository database in bytes",
+        "tableau_repository_elapsed" => "Time spent querying the repository in microseconds",
+        "tableau_repository_http_requests" => "Number of HTTP requests since the last poll",
+        "tableau_repository_http_users" => "Number of distinct users with HTTP requests since the last poll",
+        "tableau_repository_jobs_queued" => "Number of background jobs waiting in the queue",
+        "tableau_repository_jobs_running" => "Number of background jobs running",
+        "tableau_repository_jobs_long_running" => "Number of background jobs running longer than the threshold",
+        "tableau_repository_jobs_oldest_queued_age" => "Seconds the oldest queued background job has been waiting",
+        "tableau_extract_refresh" => "Extract refresh status code (0 ok, 2 failed since the last poll, 3 unavailable)",
+        "tableau_extract_refresh_failed" => "Number of extract refreshes failed since the last poll",
+        "tableau_extract_refresh_timed_out" => "Number of extract refreshes timed out since the last poll",
+        "tableau_extract_refresh_failure_job_id" => "Background job id of the failed extract refresh",
+        "tableau_extract_refresh_failure_duration" => "Seconds the failed extract refresh ran",
+        "tableau_subscription" => "Subscription and data-driven alert status code (0 ok, 2 failed or suspended since the last poll, 3 unavailable)",
+        "tableau_subscription_failed" => "Number of subscription or alert runs failed since the last poll",
+        "tableau_subscription_suspended" => "Number of subscription or alert runs failed and suspended since the last poll",
+        "tableau_subscription_skipped" => "Number of subscription or alert runs skipped or cancelled since the last poll",
+        "tableau_subscription_completed" => "Number of subscription or alert runs completed successfully since the last poll",
+        _ => "Tableau Server monitoring metric",
+    }
+}
+
+/// Replaces the characters not allowed in a metric name, or in a label name
+/// when `colon` is false, with `_`. Neither may start with a digit.
+fn sanitize(name: &str, colon: bool) -> String {
+    let name: String = name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' || (colon && c == ':') { c } else { '_' })
+        .collect();
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        std::format!("_{}", name)
+    } else {
+        name
+    }
+}
+
+fn sanitize_name(name: &str) -> String {
+    sanitize(name, true)
+}
+
+fn sanitize_label_name(name: &str) -> String {
+    sanitize(name, false)
+}
+
+fn get_gauge_value(value: &FieldValue) -> Option<GaugeValue> {
+    match value {
+        FieldValue::Integer(i) => Some(GaugeValue::Int(*i)),
+        FieldValue::Float(f) => Some(GaugeValue::Double(*f)),
+        FieldValue::Boolean(b) => Some(GaugeValue::Int(if *b { 1 } else { 0 })),
+        FieldValue::Str(_) => None,
+    }
+}
+
+fn add_sample(families: &mut Vec<GaugeFamily>, name: String, sample: GaugeSample) {
+    match families.iter_mut().find(|f| f.name == name) {
+        Some(family) => family.samples.push(sample),
+        None => families.push(GaugeFamily { name, samples: vec![sample] }),
+    }
+}
+
+/// Groups the metrics into gauge families, keeping the order in which the
+/// families first appear.
+pub fn to_gauge_families(metrics: &[Metric]) -> Vec<GaugeFamily> {
+    let mut families = Vec::new();
+
+    for metric in metrics {
+        let measurement = sanitize_name(&metric.measurement);
+        let tags: Vec<(String, String)> = metric.tags.iter()
+            .map(|(k, v)| (sanitize_label_name(k), v.clone()))
+            .collect();
+
+        for (key, value) in &metric.fields {
+            let value = match get_gauge_value(value) {
+                Some(value) => value,
+                None => continue,
+            };
+
+            if key == VALUE_FIELD {
+                let mut labels = tags.clone();
+                for (k, v) in &metric.fields {
+                    if let FieldValue::Str(s) = v {
+                        if LABEL_FIELDS.contains(&k.as_str()) {
+                            labels.push((k.clone(), s.clone()));
+                        }
+                    }
+                }
+                add_sample(&mut families, measurement.clone(),
+                           GaugeSample { labels, value, timestamp: metric.timestamp });
+            } else {
+                let name = sanitize_name(&std::format!("{}_{}", measurement, key));
+                add_sample(&mut families, name,
+                           GaugeSample { labels: tags.clone(), value, timestamp: metric.timestamp });
+            }
+        }
+    }
+
+    families
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(key: &str, value: &str) -> (String, String) {
+        (key.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn names_are_sanitized() {
+        assert_eq!(sanitize_name("tableau_tsm:status-code"), "tableau_tsm:status_code");
+        assert_eq!(sanitize_label_name("job:type"), "job_type");
+        assert_eq!(sanitize_label_name("1st node"), "_1st_node");
+    }
+
+    #[test]
+    fn metrics_are_grouped_into_families() {
+        let metrics = vec![
+            Metric::new("tableau_tsm_status")
+                .tag("node", "node1")
+                .tag("service:name", "vizqlserver")
+                .field("status_code", 1i64)
+                .field("status", "Degraded")
+                .field("message", "slow")
+                .field("transitions", 2i64),
+            Metric::new("tableau_tsm_status")
+                .tag("node", "node2")
+                .tag("service:name", "backgrounder")
+                .field("status_code", 0i64)
+                .field("status", "Running")
+                .field("transitions", 0i64)
+                .field("healthy", true),
+            Metric::new("tableau_tsm_elapsed").field("value", 1.5),
+        ];
+
+        let families = to_gauge_families(&metrics);
+        let names: Vec<&str> = families.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, vec!["tableau_tsm_status", "tableau_tsm_status_transitions",
+                               "tableau_tsm_status_healthy", "tableau_tsm_elapsed_value"]);
+
+        let status = &families[0];
+        assert_eq!(status.samples.len(), 2);
+        assert_eq!(status.samples[0].labels, vec![
+            pair("node", "node1"), pair("service_name", "vizqlserver"), pair("status", "Degraded"),
+        ]);
+        assert_eq!(status.samples[0].value, GaugeValue::Int(1));
+
+        assert_eq!(families[1].samples[1].labels, vec![pair("node", "node2"), pair("service_name", "backgrounder")]);
+        assert_eq!(families[2].samples[0].value, GaugeValue::Int(1));
+        assert_eq!(families[3].samples[0].value, GaugeValue::Double(1.5));
+        assert!(families[3].samples[0].labels.is_empty());
+    }
+}
@@ -0,0 +1,96 @@
+//! InfluxDB v2 write API exporter.
+
+use std::error::Error;
+use std::io::Write;
+use std::time::Duration;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use ureq::Agent;
+
+use crate::line_protocol::format_metric;
+use crate::metric::Collection;
+use crate::Exporter;
+
+/// Delay before the first retry, doubled on every further attempt.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+pub struct InfluxDbExporter {
+    agent: Agent,
+    url: String,
+    org: String,
+    bucket: String,
+    token: String,
+    retries: u32,
+}
+
+fn is_retryable(error: &ureq::Error) -> bool {
+    match error {
+        ureq::Error::Status(code, _) => *code == 429 || *code >= 500,
+        ureq::Error::Transport(_) => true,
+    }
+}
+
+/// Delay requested by the server through `Retry-After`, if any.
+fn get_retry_after(error: &ureq::Error) -> Option<Duration> {
+    match error {
+        ureq::Error::Status(_, response) => response.header("Retry-After")
+            .and_then(|v| v.trim().parse().ok())
+            .map(Duration::from_secs),
+        ureq::Error::Transport(_) => None,
+    }
+}
+
+impl InfluxDbExporter {
+    /// `url` is the base URL of the InfluxDB server, `api/v2/write` is appended to it.
+    pub fn new(agent: &Agent, url: &str, org: &str, bucket: &str, token: &str, retries: u32) -> Self {
+        InfluxDbExporter {
+            agent: agent.clone(),
+            url: std::format!("{}api/v2/write", url),
+            org: org.to_string(),
+            bucket: bucket.to_string(),
+            token: token.to_string(),
+            retries,
+        }
+    }
+
+    fn build_request(&self) -> ureq::Request {
+        self.agent.post(&self.url)
+            .query("org", &self.org)
+            .query("bucket", &self.bucket)
+            .query("precision", "ns")
+            .set("Authorization", &std::format!("Token {}", self.token))
+            .set("Content-Type", "text/plain; charset=utf-8")
+            .set("Content-Encoding", "gzip")
+    }
+}
+
+impl Exporter for InfluxDbExporter {
+    fn name(&self) -> &'static str {
+        "influxdb"
+    }
+
+    fn export(&mut self, collection: &Collection) -> Result<(), Box<dyn Error>> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        for line in collection.metrics.iter().filter_map(format_metric) {
+            writeln!(encoder, "{}", line)?;
+        }
+        let body = encoder.finish()?;
+
+        let mut backoff = INITIAL_BACKOFF;
+        let mut attempt = 0;
+        loop {
+            match self.build_request().send_bytes(&body) {
+                Ok(_) => return Ok(()),
+                Err(e) if attempt < self.retries && is_retryable(&e) => {
+                    let delay = get_retry_after(&e).unwrap_or(backoff);
+                    eprintln!("influxdb write failed, retrying in {}s: {}", delay.as_secs(), e);
+                    std::thread::sleep(delay);
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                Err(e) => return Err(Box::new(e)),
+            }
+        }
+    }
+}
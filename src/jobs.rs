@@ -0,0 +1,106 @@
+//! TSM asynchronous job monitoring (apply changes, backups, ziplogs, restarts, upgrades).
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::CheckError;
+use crate::metric::{get_epoch_millis, Collection, Metric};
+use crate::tsm::TsmClient;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AsyncJobs {
+    async_jobs: Vec<AsyncJob>,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AsyncJob {
+    pub id: String,
+    pub status: String,
+    pub job_type: String,
+    /// Epoch milliseconds.
+    pub created_at: Option<u64>,
+    pub updated_at: Option<u64>,
+    pub completed_at: Option<u64>,
+    pub progress: Option<f64>,
+    pub status_message: Option<String>,
+    /// Job specific attributes, such as the arguments of the job.
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+impl AsyncJob {
+    pub fn is_running(&self) -> bool {
+        self.status == "Running" || self.status == "Created" || self.status == "Queued"
+    }
+
+    /// Time the job finished, or was last updated when TSM did not set
+    /// `completedAt`.
+    pub fn finished_at(&self) -> Option<u64> {
+        self.completed_at.or(self.updated_at)
+    }
+}
+
+pub fn get_async_jobs(tsm: &TsmClient) -> Result<Vec<AsyncJob>, CheckError> {
+    let jobs: AsyncJobs = tsm.get_json("asyncJobs")?;
+
+    Ok(jobs.async_jobs)
+}
+
+/// Reports the number of jobs by type and status, the age of the oldest
+/// running job and a status code that turns to error when a job failed
+/// within `failure_window` or has been running longer than `running_threshold`.
+pub fn check_tsm_jobs(tsm: &TsmClient, running_threshold: Duration, failure_window: Duration,
+                      collection: &mut Collection) -> Result<(), CheckError> {
+    let jobs = get_async_jobs(tsm)?;
+    let now = get_epoch_millis();
+
+    let mut counts: BTreeMap<(&str, &str), i64> = BTreeMap::new();
+    for job in &jobs {
+        *counts.entry((&job.job_type, &job.status)).or_insert(0) += 1;
+    }
+
+    for ((job_type, status), count) in &counts {
+        collection.push(Metric::new("tableau_tsm_jobs")
+            .tag("job_type", job_type)
+            .tag("job_status", status)
+            .field("count", *count));
+    }
+
+    let running: Vec<&AsyncJob> = jobs.iter().filter(|j| j.is_running()).collect();
+    let oldest_running_age = running.iter()
+        .filter_map(|j| j.created_at)
+        .map(|created_at| now.saturating_sub(created_at) / 1000)
+        .max()
+        .unwrap_or(0);
+    let stuck = oldest_running_age > running_threshold.as_secs();
+
+    let window_start = now.saturating_sub(failure_window.as_millis() as u64);
+    let recent_failures = jobs.iter()
+        .filter(|j| j.status == "Failed" && j.finished_at().is_some_and(|t| t >= window_start))
+        .count() as i64;
+
+    let (status_code, status) = if stuck {
+        (2i64, "Stuck")
+    } else if recent_failures > 0 {
+        (2i64, "Failed")
+    } else {
+        (0i64, "Ok")
+    };
+
+    collection.push(Metric::new("tableau_tsm_jobs")
+        .tag("job_type", "all")
+        .tag("job_status", "all")
+        .field("status_code", status_code)
+        .field("status", status)
+        .field("running", running.len() as i64)
+        .field("recent_failures", recent_failures)
+        .field("oldest_running_age", oldest_running_age));
+
+    collection.set_detail("jobs", serde_json::to_value(&jobs)?);
+
+    Ok(())
+}
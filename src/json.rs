@@ -0,0 +1,33 @@
+use std::io::Write;
+
+use serde_json::{Map, Value};
+
+use crate::metric::Collection;
+
+/// Builds the JSON document of a collection: the timestamp, the structured
+/// output of every check that succeeded keyed by the check name, and an
+/// `errors` object for the checks that failed. Named clusters are nested
+/// under `clusters.<name>` with their own `errors` object.
+pub fn to_document(collection: &Collection) -> Value {
+    let mut document = Map::new();
+    document.insert("timestamp".to_string(), Value::from(collection.timestamp as u64));
+
+    for (check, detail) in &collection.details {
+        document.insert(check.clone(), detail.clone());
+    }
+
+    if !collection.errors.is_empty() {
+        let errors = collection.errors.iter()
+            .map(|(check, error)| (check.clone(), Value::from(error.as_str())))
+            .collect();
+        document.insert("errors".to_string(), Value::Object(errors));
+    }
+
+    Value::Object(document)
+}
+
+/// Writes the collection as a single line of JSON.
+pub fn write_collection(out: &mut dyn Write, collection: &Collection) -> std::io::Result<()> {
+    serde_json::to_writer(&mut *out, &to_document(collection))?;
+    writeln!(out)
+}
@@ -1,10 +1,12 @@
-use ureq::{Agent, AgentBuilder, Cookie};
-use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use ureq::{Agent, AgentBuilder};
+use std::time::{Duration, Instant};
 use std::sync::Arc;
-use serde::{Deserialize};
+use std::collections::HashSet;
+use std::str::FromStr;
+use serde::{Deserialize, Serialize};
 use clap::ArgMatches;
 use std::error::Error;
-use std::io::BufRead;
+use std::io::{BufRead, Write};
 
 #[cfg(unix)]
 use std::os::unix::net::UnixStream;
@@ -14,81 +16,221 @@ use thrift::protocol::{TBinaryInputProtocol, TBinaryOutputProtocol};
 use users::{switch::set_current_uid, get_effective_uid};
 
 mod tls;
+mod error;
+mod datetime;
+mod hash;
+mod cluster;
+mod tsm;
+mod jobs;
+mod licensing;
+mod pending_changes;
+mod backup;
+mod topology;
+mod configuration;
+mod transitions;
+mod rest;
+mod sites;
+mod poll;
+mod backgrounder;
+mod repository;
+mod extracts;
+mod subscriptions;
+#[allow(clippy::all)]
 mod passwordless_login;
+mod metric;
+mod line_protocol;
+mod gauge;
+mod prometheus;
+mod json;
+mod otlp;
+mod influxdb;
+mod zabbix;
+mod nagios;
+mod schedule;
+mod server;
 
 pub use passwordless_login::*;
+pub use metric::{Metric, FieldValue, Collection};
+pub use schedule::parse_duration;
+pub use cluster::Cluster;
+use tsm::{TsmClient, TsmSession};
+use rest::RestSession;
+use repository::RepositorySession;
+use error::CheckError;
 
 
-#[derive(Deserialize)]
+fn get_unknown() -> String {
+    "Unknown".to_string()
+}
+
+// Missing fields get defaults and identifiers may be numbers, so the field
+// differences between TSM API versions do not fail the whole status check.
+#[derive(Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 struct ClusterStatus {
     cluster_status: ClusterStatus_
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 struct ClusterStatus_ {
+    #[serde(default)]
     nodes: Vec<NodeStatus>,
+    #[serde(default = "get_unknown")]
     rollup_status: String,
+    #[serde(default = "get_unknown")]
     rollup_requested_deployment_state: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 struct NodeStatus {
+    #[serde(default)]
     services: Vec<ServiceStatus>,
+    #[serde(deserialize_with = "tsm::deserialize_lenient_string")]
     node_id: String,
+    #[serde(default = "get_unknown")]
     rollup_status: String,
+    #[serde(default = "get_unknown")]
     rollup_requested_deployment_state: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 struct ServiceStatus {
     service_name: String,
+    #[serde(default)]
     instances: Vec<InstanceStatus>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 struct InstanceStatus {
+    #[serde(default)]
     code: Option<String>,
+    #[serde(default = "get_unknown")]
     process_status: String,
+    #[serde(deserialize_with = "tsm::deserialize_lenient_string")]
     instance_id: String,
+    #[serde(default)]
     message: Option<String>,
+    #[serde(default, deserialize_with = "tsm::deserialize_lenient_u64")]
     timestamp_utc: u64,
+    #[serde(default = "get_unknown")]
     current_deployment_state: String,
 }
 
-fn get_status_as_value(status: &String, deployment: Option<&String>) -> i8 {
+/// Format used to render the collected metrics.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Influx,
+    Prometheus,
+    Json,
+    None,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "influx" => Ok(OutputFormat::Influx),
+            "prometheus" => Ok(OutputFormat::Prometheus),
+            "json" => Ok(OutputFormat::Json),
+            "none" => Ok(OutputFormat::None),
+            _ => Err(std::format!("Unknown output format: {}", s)),
+        }
+    }
+}
+
+pub fn write_collection(out: &mut dyn Write, format: OutputFormat, collection: &Collection) -> std::io::Result<()> {
+    match format {
+        OutputFormat::Influx => line_protocol::write_metrics(out, &collection.metrics),
+        OutputFormat::Prometheus => prometheus::write_metrics(out, &collection.metrics),
+        OutputFormat::Json => json::write_collection(out, collection),
+        OutputFormat::None => Ok(()),
+    }
+}
+
+/// Destination the collected metrics are pushed to besides the standard output.
+pub trait Exporter {
+    fn name(&self) -> &'static str;
+
+    fn export(&mut self, collection: &Collection) -> Result<(), Box<dyn Error>>;
+}
+
+fn build_exporters(agent: &Agent, args: &ArgMatches, clusters: &[Cluster]) -> Vec<Box<dyn Exporter>> {
+    let mut exporters: Vec<Box<dyn Exporter>> = Vec::new();
+
+    if let Some(endpoint) = args.value_of("otlp_endpoint") {
+        let mut resource_attributes = vec![
+            ("service.name".to_string(), env!("CARGO_PKG_NAME").to_string()),
+            ("service.version".to_string(), env!("CARGO_PKG_VERSION").to_string()),
+        ];
+        resource_attributes.extend(otlp::parse_key_value_list(
+            args.value_of("otlp_resource_attributes").unwrap_or("")));
+
+        let cluster_resources = clusters.iter()
+            .map(|cluster| {
+                let mut attributes = Vec::new();
+                if let Some(name) = &cluster.name {
+                    attributes.push(("tableau.cluster".to_string(), name.clone()));
+                }
+                attributes.push(("tableau.tsm.url".to_string(), cluster.tsm_hostname.clone()));
+                attributes.push(("tableau.systeminfo.url".to_string(), cluster.systeminfo_hostname.clone()));
+                otlp::ClusterResource { name: cluster.name.clone(), attributes }
+            })
+            .collect();
+
+        exporters.push(Box::new(otlp::OtlpExporter::new(
+            agent,
+            endpoint,
+            otlp::parse_key_value_list(args.value_of("otlp_headers").unwrap_or("")),
+            resource_attributes,
+            cluster_resources)));
+    }
+
+    if let Some(url) = args.value_of("influxdb_url") {
+        exporters.push(Box::new(influxdb::InfluxDbExporter::new(
+            agent,
+            url,
+            args.value_of("influxdb_org").expect("InfluxDB organization must be defined"),
+            args.value_of("influxdb_bucket").expect("InfluxDB bucket must be defined"),
+            args.value_of("influxdb_token").expect("InfluxDB token must be defined"),
+            args.value_of_t("influxdb_retries").unwrap_or_else(|e| e.exit()))));
+    }
+
+    if let Some(server) = args.value_of("zabbix_server") {
+        exporters.push(Box::new(zabbix::ZabbixSender::new(
+            server,
+            args.value_of("zabbix_host").expect("Zabbix host must be defined"))));
+    }
+
+    exporters
+}
 
+fn export(exporters: &mut [Box<dyn Exporter>], collection: &Collection) {
+    for exporter in exporters.iter_mut() {
+        if let Err(e) = exporter.export(collection) {
+            eprintln!("{} export error: {}", exporter.name(), e);
+        }
+    }
+}
 
+fn get_status_as_value(status: &str, deployment: Option<&str>) -> i8 {
     if status.eq("Active") || status.eq("Enabled") || status.eq("Running") {
         0
     } else if status.eq("Busy") || status.eq("Passive") {
         1
     } else {
         match deployment {
-            None => 2,
-            Some(state) => {
-                if state.eq("Disabled") {
-                    -1
-                } else {
-                    2
-                }
-            }
+            Some(state) if state.eq("Disabled") => -1,
+            _ => 2,
         }
     }
 }
 
-fn get_epoch_nanos() -> u128 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_nanos()
-}
-
-fn get_system_info_xml(agent: &Agent, url: &String) -> Result<(String, u128), ureq::Error> {
+fn get_system_info_xml(agent: &Agent, url: &str) -> Result<(String, u128), CheckError> {
     let start = Instant::now();
 
     let xml_server_info = agent.get(url)
@@ -98,142 +240,131 @@ fn get_system_info_xml(agent: &Agent, url: &String) -> Result<(String, u128), ur
     Ok((xml_server_info, start.elapsed().as_micros()))
 }
 
-fn parse_system_info(xml: &String, elapsed: u128) -> Result<(), roxmltree::Error> {
-    let doc = roxmltree::Document::parse(&*xml)?;
+fn parse_system_info(xml: &str, elapsed: u128, collection: &mut Collection) -> Result<(), roxmltree::Error> {
+    let doc = roxmltree::Document::parse(xml)?;
+    let mut service_status = "Unknown";
+    let mut processes = Vec::new();
 
     for node in doc.descendants() {
         let tag_name = node.tag_name().name();
 
-        if tag_name == "" || tag_name == "systeminfo" || tag_name == "machines" || tag_name == "machine" {
+        if tag_name.is_empty() || tag_name == "systeminfo" || tag_name == "machines" || tag_name == "machine" {
             continue;
         }
 
         let status = node.attribute("status").unwrap_or("Unknown");
 
         if tag_name == "service" {
-            println!("tableau_systeminfo,worker=all status_code={}i,status=\"{}\",elapsed={}i {}"
-                     , get_status_as_value(&status.to_string(),None)
-                     , status
-                     , elapsed
-                     , get_epoch_nanos())
+            service_status = status;
+            collection.push(Metric::new("tableau_systeminfo")
+                .tag("worker", "all")
+                .field("status_code", get_status_as_value(status, None))
+                .field("status", status)
+                .field("elapsed", elapsed));
         } else {
             let worker = node.attribute("worker").unwrap_or("Unknown");
-            println!("tableau_systeminfo,process={},worker={} status_code={}i,status=\"{}\" {}"
-                     , tag_name
-                     , worker
-                     , get_status_as_value(&status.to_string(),None)
-                     , status
-                     , get_epoch_nanos());
+            processes.push(serde_json::json!({
+                "process": tag_name,
+                "worker": worker,
+                "status": status,
+            }));
+            collection.push(Metric::new("tableau_systeminfo")
+                .tag("process", tag_name)
+                .tag("worker", worker)
+                .field("status_code", get_status_as_value(status, None))
+                .field("status", status));
         }
     };
 
+    collection.set_detail("systeminfo", serde_json::json!({
+        "status": service_status,
+        "elapsed": elapsed as u64,
+        "processes": processes,
+    }));
+
     Ok(())
 }
 
-fn check_system_info(agent: &Agent, url: &str) -> Result<(), Box<dyn Error>> {
+fn check_system_info(agent: &Agent, url: &str, collection: &mut Collection) -> Result<(), CheckError> {
     let url = std::format!("{}admin/systeminfo.xml", url);
 
-    match get_system_info_xml(&agent, &url) {
-        Ok((xml, elapsed)) => Ok(parse_system_info(&xml, elapsed)?),
-        Err(e) => Err(Box::new(e))
-    }
+    let (xml, elapsed) = get_system_info_xml(agent, &url)?;
+    Ok(parse_system_info(&xml, elapsed, collection)?)
 }
 
-fn get_passwordless_cookie(name: Option<String>, value: Option<String>) -> String {
-    match (name,value) {
-        (Some(name),Some(value)) => {
-            Cookie::build(name, value)
-                .domain("localhost")
-                .path("/")
-                .secure(true)
-                .http_only(true)
-                .finish()
-                .to_string()
-        },
-        _ => "".to_string()
-    }
-}
-
-fn check_tsm_nodes(agent: &Agent, args: &ArgMatches) -> Result<(), Box<dyn Error>> {
-    let tsm_host = args.value_of("tsm_hostname").expect("tsm_hostname must be defined");
-
-    let logon_url = std::format!("{}api/0.5/login",tsm_host);
-    let status_url = std::format!("{}api/0.5/status", tsm_host);
-
+fn check_tsm_nodes(tsm: &TsmClient, tracker: &transitions::StateTracker, cluster: &str,
+                   collection: &mut Collection) -> Result<(), CheckError> {
     let start = Instant::now();
 
-    let status_req;
-
-
-    if cfg!(unix) && args.is_present("passwordless") {
-        let tsm_socket = args.value_of("tsm_socket").expect("TSM socket must be defined");
-        let login_result = get_passwordless_result(tsm_socket)?;
-        let cookie = get_passwordless_cookie(login_result.cookie_name, login_result.cookie_value);
-        status_req = agent.get(&status_url)
-            .set("Cookie", &cookie.as_str());
-    } else {
-        agent.post(&logon_url)
-            .send_json(ureq::json!({
-            "authentication": {
-                "name": args.value_of("tsm_user").expect("TSM username must be defined") ,
-                "password": args.value_of("tsm_password").expect("TSM password var must be defined")
-            }}))?
-            .into_string()?;
-        status_req = agent.get(&status_url);
-    }
-
-
-    let status: ClusterStatus = status_req
-        .call()?
-        .into_json()?;
+    let status: ClusterStatus = tsm.get_json("status")?;
     let cluster_status = status.cluster_status;
+    let elapsed = start.elapsed().as_micros();
+
+    let mut detail = serde_json::to_value(&cluster_status)?;
+    detail["elapsed"] = serde_json::Value::from(elapsed as u64);
+    collection.set_detail("tsm", detail);
 
     // Cluster level
-    println!("tableau_tsm_status,node=all,service=all,instance=all status_code={}i,status=\"{}\"\
-        ,requested_deployment_state=\"{}\",elapsed={}i {}",
-             get_status_as_value(&cluster_status.rollup_status,
-                                 Some(&cluster_status.rollup_requested_deployment_state)),
-             cluster_status.rollup_status,
-             cluster_status.rollup_requested_deployment_state,
-             start.elapsed().as_micros(),
-             get_epoch_nanos()
-    );
+    collection.push(Metric::new("tableau_tsm_status")
+        .tag("node", "all")
+        .tag("service", "all")
+        .tag("instance", "all")
+        .field("status_code", get_status_as_value(&cluster_status.rollup_status,
+                                                  Some(&cluster_status.rollup_requested_deployment_state)))
+        .field("status", cluster_status.rollup_status.as_str())
+        .field("requested_deployment_state", cluster_status.rollup_requested_deployment_state.as_str())
+        .field("elapsed", elapsed));
 
     // Node Level
+    let now = metric::get_epoch_millis();
+    let mut seen = HashSet::new();
     for node in cluster_status.nodes {
-        println!("tableau_tsm_status,node={},service=all,instance=all \
-            status_code={}i,status=\"{}\",requested_deployment_state=\"{}\" {}",
-                 node.node_id,
-                 get_status_as_value(&node.rollup_status,
-                                     Some(&node.rollup_requested_deployment_state)),
-                 node.rollup_status,
-                 node.rollup_requested_deployment_state,
-                 get_epoch_nanos()
-        );
+        collection.push(Metric::new("tableau_tsm_status")
+            .tag("node", &node.node_id)
+            .tag("service", "all")
+            .tag("instance", "all")
+            .field("status_code", get_status_as_value(&node.rollup_status,
+                                                      Some(&node.rollup_requested_deployment_state)))
+            .field("status", node.rollup_status.as_str())
+            .field("requested_deployment_state", node.rollup_requested_deployment_state.as_str()));
 
         // Instance Level
         for service in node.services {
             for instance in service.instances {
-                println!("tableau_tsm_status,node={},service={},instance={} \
-                            status_code={}i,status=\"{}\",deployment_state=\"{}\"\
-                            ,message=\"{}\",code=\"{}\",timestamp_utc={}i \
-                            {}",
-                         node.node_id,
-                         service.service_name,
-                         instance.instance_id,
-                         get_status_as_value(&instance.process_status,
-                                             Some(&instance.current_deployment_state)),
-                         instance.process_status,
-                         instance.current_deployment_state,
-                         instance.message.unwrap_or("".to_string()),
-                         instance.code.unwrap_or("".to_string()),
-                         instance.timestamp_utc,
-                         get_epoch_nanos()
-                );
+                let key = (node.node_id.clone(), service.service_name.clone(), instance.instance_id.clone());
+                seen.insert(key.clone());
+                let observation = tracker.observe(cluster, key, &instance.process_status,
+                                                  instance.timestamp_utc, now);
+                let status_code = get_status_as_value(&instance.process_status,
+                                                      Some(&instance.current_deployment_state));
+
+                if let Some((previous_status, previous_seconds)) = &observation.previous {
+                    collection.push(Metric::new("tableau_tsm_transition")
+                        .tag("node", &node.node_id)
+                        .tag("service", &service.service_name)
+                        .tag("instance", &instance.instance_id)
+                        .field("status", instance.process_status.as_str())
+                        .field("previous_status", previous_status.as_str())
+                        .field("previous_seconds_in_state", *previous_seconds));
+                }
+
+                collection.push(Metric::new("tableau_tsm_status")
+                    .tag("node", &node.node_id)
+                    .tag("service", &service.service_name)
+                    .tag("instance", &instance.instance_id)
+                    .field("status_code", status_code)
+                    .field("status", instance.process_status)
+                    .field("deployment_state", instance.current_deployment_state)
+                    .field("message", instance.message.unwrap_or_default())
+                    .field("code", instance.code.unwrap_or_default())
+                    .field("timestamp_utc", instance.timestamp_utc)
+                    .field("seconds_in_state", observation.seconds_in_state)
+                    .field("transitions", observation.transitions));
             }
         }
     }
 
+    tracker.retain(cluster, &seen);
 
     Ok(())
 }
@@ -247,9 +378,7 @@ pub fn get_passwordless_result(socket_path: &str) -> thrift::Result<PasswordLess
     let out_proto = TBinaryOutputProtocol::new(socket_rx, true);
     let mut client = PasswordLessLoginSyncClient::new(in_proto, out_proto);
 
-    let passwordless_login_result = client.login();
-
-    passwordless_login_result
+    client.login()
 }
 
 #[cfg(windows)]
@@ -258,47 +387,553 @@ pub fn get_passwordless_result(socket_path: &str) -> thrift::Result<PasswordLess
 }
 
 #[cfg(feature = "setuid")]
-pub fn change_current_uid() -> () {
+pub fn change_current_uid() {
 
     if let Err(e) = set_current_uid(get_effective_uid()) {
-        eprintln!("Cannot set uid: {}", e.to_string());
+        eprintln!("Cannot set uid: {}", e);
     }
 }
 
-pub fn run(args: &ArgMatches) {
-    let hostname = args.value_of("systeminfo_hostname").expect("Missing Server hostname");
-    let checks = args.value_of("checks").expect("No checks are defined.");
+/// Thresholds and settings of the checks, shared by all clusters.
+pub struct CheckOptions {
+    pub jobs_running_threshold: Duration,
+    pub jobs_failure_window: Duration,
+    pub license_warning_days: i64,
+    pub backup_max_age: Duration,
+    pub long_running_job_threshold: Duration,
+}
 
-    #[cfg(feature = "setuid")]
-    change_current_uid();
+impl CheckOptions {
+    fn from_args(args: &ArgMatches) -> Self {
+        let get_duration = |name: &str| {
+            parse_duration(args.value_of(name).expect("duration has a default value"))
+                .expect("duration is validated")
+        };
+
+        CheckOptions {
+            jobs_running_threshold: get_duration("jobs_running_threshold"),
+            jobs_failure_window: get_duration("jobs_failure_window"),
+            license_warning_days: args.value_of_t("license_warning_days").unwrap_or_else(|e| e.exit()),
+            backup_max_age: get_duration("backup_max_age"),
+            long_running_job_threshold: get_duration("long_running_job_threshold"),
+        }
+    }
+}
+
+fn get_client<T>(client: &Option<Result<T, CheckError>>) -> Result<&T, CheckError> {
+    match client {
+        Some(Ok(client)) => Ok(client),
+        Some(Err(e)) => Err(e.clone()),
+        None => Err("login was skipped".into()),
+    }
+}
+
+/// Checks that need a TSM login.
+const TSM_CHECKS: &[&str] = &["tsm", "jobs", "licensing", "pending_changes", "backup", "topology", "configuration"];
+
+/// Checks that need a REST API sign-in.
+const REST_CHECKS: &[&str] = &["sites", "backgrounder"];
+
+/// Sessions of a cluster, kept between collections.
+struct Sessions {
+    tsm: TsmSession,
+    rest: RestSession,
+    repository: RepositorySession,
+}
+
+impl Sessions {
+    fn new(cluster: &Cluster) -> Self {
+        Sessions { tsm: TsmSession::new(cluster), rest: RestSession::new(cluster), repository: Default::default() }
+    }
+}
+
+/// Runs the selected checks against every configured cluster.
+pub struct Collector {
+    agent: Agent,
+    clusters: Vec<Cluster>,
+    /// One per cluster.
+    sessions: Vec<Sessions>,
+    checks: Vec<String>,
+    options: CheckOptions,
+    pending_changes: pending_changes::PendingChangesTracker,
+    configuration: configuration::ConfigurationTracker,
+    transitions: transitions::StateTracker,
+    backgrounder: poll::PollTracker,
+    repository: poll::PollTracker,
+    extract_refreshes: poll::PollTracker,
+    subscriptions: poll::PollTracker,
+}
+
+impl Collector {
+    pub fn from_args(args: &ArgMatches) -> Self {
+        let clusters = match args.value_of("clusters_file") {
+            Some(path) => Cluster::from_file(path).unwrap_or_else(|e| {
+                eprintln!("Cannot load clusters: {}", e);
+                std::process::exit(2);
+            }),
+            None => vec![Cluster::from_args(args)],
+        };
+
+        Collector {
+            agent: build_agent(),
+            sessions: clusters.iter().map(Sessions::new).collect(),
+            clusters,
+            checks: args.values_of("checks").expect("No checks are defined.")
+                .map(str::to_string)
+                .collect(),
+            options: CheckOptions::from_args(args),
+            pending_changes: Default::default(),
+            configuration: Default::default(),
+            transitions: Default::default(),
+            backgrounder: Default::default(),
+            repository: Default::default(),
+            extract_refreshes: Default::default(),
+            subscriptions: Default::default(),
+        }
+    }
+
+    fn is_enabled(&self, check: &str) -> bool {
+        self.checks.iter().any(|c| c == "all" || c == check)
+    }
+
+    /// `all` only includes the REST API checks of clusters with a Personal
+    /// Access Token, so it keeps working without one.
+    fn is_rest_enabled(&self, cluster: &Cluster, check: &str) -> bool {
+        self.checks.iter().any(|c| c == check) || (self.is_enabled(check) && cluster.pat_name.is_some())
+    }
+
+    /// Like the REST API checks, `all` only includes the repository checks
+    /// of clusters with a repository host.
+    fn is_repository_enabled(&self, cluster: &Cluster, check: &str) -> bool {
+        self.checks.iter().any(|c| c == check) || (self.is_enabled(check) && cluster.repository_host.is_some())
+    }
+
+    fn collect_cluster(&self, cluster: &Cluster, sessions: &Sessions) -> Collection {
+        let agent = &self.agent;
+        let label = cluster.name.as_ref().map(|n| std::format!(" ({})", n)).unwrap_or_default();
+        let mut collection = Collection::new();
+
+        let report = |collection: &mut Collection, check: &str, function: &str, e: CheckError, unavailable: Metric| {
+            let unavailable = unavailable.tag("error_kind", e.kind());
+            collection.push(match e.http_status() {
+                Some(code) => unavailable.field("http_status", i64::from(code)),
+                None => unavailable,
+            });
+            collection.add_error(check, &e);
+            eprintln!("{} error{}: {}", function, label, e);
+        };
+
+        // the TSM session is reused across collections, a failed login is
+        // reported by every TSM check
+        let tsm_enabled = TSM_CHECKS.iter().any(|c| self.is_enabled(c));
+        let tsm = if tsm_enabled {
+            Some(sessions.tsm.connect(cluster))
+        } else {
+            None
+        };
+
+        if self.is_enabled("tsm") {
+            let result = get_client(&tsm).and_then(|tsm| {
+                check_tsm_nodes(tsm, &self.transitions, cluster.name.as_deref().unwrap_or(""), &mut collection)
+            });
+            if let Err(e) = result {
+                report(&mut collection, "tsm", "check_tsm_nodes", e, Metric::new("tableau_tsm_status")
+                    .tag("node", "all")
+                    .tag("service", "all")
+                    .tag("instance", "all")
+                    .field("status_code", 3i64)
+                    .field("status", "Unavailable")
+                    .field("requested_deployment_state", "Unknown"));
+            }
+        }
+
+        if self.is_enabled("jobs") {
+            let result = get_client(&tsm).and_then(|tsm| {
+                jobs::check_tsm_jobs(tsm, self.options.jobs_running_threshold,
+                                     self.options.jobs_failure_window, &mut collection)
+            });
+            if let Err(e) = result {
+                report(&mut collection, "jobs", "check_tsm_jobs", e, Metric::new("tableau_tsm_jobs")
+                    .tag("job_type", "all")
+                    .tag("job_status", "all")
+                    .field("status_code", 3i64)
+                    .field("status", "Unavailable"));
+            }
+        }
 
+        if self.is_enabled("licensing") {
+            let result = get_client(&tsm).and_then(|tsm| {
+                licensing::check_licensing(tsm, self.options.license_warning_days, &mut collection)
+            });
+            if let Err(e) = result {
+                report(&mut collection, "licensing", "check_licensing", e, Metric::new("tableau_license")
+                    .tag("product_key", "all")
+                    .tag("product", "all")
+                    .field("status_code", 3i64)
+                    .field("status", "Unavailable"));
+            }
+        }
+
+        if self.is_enabled("pending_changes") {
+            let result = get_client(&tsm).and_then(|tsm| {
+                pending_changes::check_pending_changes(tsm, &self.pending_changes,
+                                                       cluster.name.as_deref().unwrap_or(""), &mut collection)
+            });
+            if let Err(e) = result {
+                report(&mut collection, "pending_changes", "check_pending_changes", e,
+                       Metric::new("tableau_tsm_pending_changes")
+                           .tag("key", "all")
+                           .field("status_code", 3i64)
+                           .field("status", "Unavailable"));
+            }
+        }
+
+        if self.is_enabled("backup") {
+            let result = get_client(&tsm).and_then(|tsm| {
+                backup::check_backup(tsm, self.options.backup_max_age, &mut collection)
+            });
+            if let Err(e) = result {
+                report(&mut collection, "backup", "check_backup", e, Metric::new("tableau_backup")
+                    .field("status_code", 3i64)
+                    .field("status", "Unavailable"));
+            }
+        }
+
+        if self.is_enabled("topology") {
+            let result = get_client(&tsm).and_then(|tsm| topology::check_topology(tsm, &mut collection));
+            if let Err(e) = result {
+                report(&mut collection, "topology", "check_topology", e, Metric::new("tableau_node_info")
+                    .tag("node", "all")
+                    .tag("service", "all")
+                    .field("status_code", 3i64)
+                    .field("status", "Unavailable"));
+            }
+        }
+
+        if self.is_enabled("configuration") {
+            let result = get_client(&tsm).and_then(|tsm| {
+                configuration::check_configuration(tsm, &self.configuration,
+                                                   cluster.name.as_deref().unwrap_or(""), &mut collection)
+            });
+            if let Err(e) = result {
+                report(&mut collection, "configuration", "check_configuration", e, Metric::new("tableau_tsm_config")
+                    .field("status_code", 3i64)
+                    .field("status", "Unavailable"));
+            }
+        }
+
+        let rest_enabled = REST_CHECKS.iter().any(|c| self.is_rest_enabled(cluster, c));
+        let rest = if rest_enabled {
+            Some(sessions.rest.connect(cluster))
+        } else {
+            None
+        };
+
+        if self.is_rest_enabled(cluster, "sites") {
+            if let Err(e) = get_client(&rest).and_then(|rest| sites::check_sites(rest, &mut collection)) {
+                report(&mut collection, "sites", "check_sites", e, Metric::new("tableau_site")
+                    .tag("site", "all")
+                    .field("status_code", 3i64)
+                    .field("status", "Unavailable"));
+            }
+        }
+
+        if self.is_rest_enabled(cluster, "backgrounder") {
+            let result = get_client(&rest).and_then(|rest| {
+                backgrounder::check_backgrounder(rest, &self.backgrounder,
+                                                 cluster.name.as_deref().unwrap_or(""), &mut collection)
+            });
+            if let Err(e) = result {
+                report(&mut collection, "backgrounder", "check_backgrounder", e, Metric::new("tableau_backgrounder")
+                    .tag("site", "all")
+                    .tag("job_type", "all")
+                    .field("status_code", 3i64)
+                    .field("status", "Unavailable"));
+            }
+        }
+
+        if self.is_repository_enabled(cluster, "repository") {
+            let result = repository::check_repository(&sessions.repository, cluster, &self.repository,
+                                                      self.options.long_running_job_threshold, &mut collection);
+            if let Err(e) = result {
+                report(&mut collection, "repository", "check_repository", e, Metric::new("tableau_repository")
+                    .field("status_code", 3i64)
+                    .field("status", "Unavailable"));
+            }
+        }
+
+        if self.is_repository_enabled(cluster, "extract_refreshes") {
+            let result = extracts::check_extract_refreshes(&sessions.repository, cluster, &self.extract_refreshes,
+                                                           &mut collection);
+            if let Err(e) = result {
+                report(&mut collection, "extract_refreshes", "check_extract_refreshes", e,
+                       Metric::new("tableau_extract_refresh")
+                           .field("status_code", 3i64)
+                           .field("status", "Unavailable"));
+            }
+        }
+
+        if self.is_repository_enabled(cluster, "subscriptions") {
+            let result = subscriptions::check_subscriptions(&sessions.repository, cluster, &self.subscriptions,
+                                                            &mut collection);
+            if let Err(e) = result {
+                report(&mut collection, "subscriptions", "check_subscriptions", e, Metric::new("tableau_subscription")
+                    .tag("site", "all")
+                    .tag("kind", "all")
+                    .field("status_code", 3i64)
+                    .field("status", "Unavailable"));
+            }
+        }
+
+        if self.is_enabled("systeminfo") {
+            if let Err(e) = check_system_info(agent, &cluster.systeminfo_hostname, &mut collection) {
+                report(&mut collection, "systeminfo", "check_system_info", e, Metric::new("tableau_systeminfo")
+                    .tag("worker", "all")
+                    .field("status_code", 3i64)
+                    .field("status", "Unavailable"));
+            }
+        }
+
+        if tsm_enabled {
+            sessions.tsm.add_metrics(&mut collection);
+        }
+        if rest_enabled {
+            sessions.rest.add_metrics(&mut collection);
+        }
+
+        collection
+    }
+
+    /// Polls the clusters in parallel, so an unreachable cluster does not
+    /// delay the others.
+    pub fn collect(&self) -> Collection {
+        let mut collection = Collection::new();
+
+        let results: Vec<Collection> = std::thread::scope(|scope| {
+            let handles: Vec<_> = self.clusters.iter().zip(&self.sessions)
+                .map(|(cluster, sessions)| scope.spawn(move || self.collect_cluster(cluster, sessions)))
+                .collect();
+            // a panic in one cluster's checks must not stop the others
+            handles.into_iter()
+                .map(|handle| handle.join().unwrap_or_else(|_| {
+                    let mut failed = Collection::new();
+                    failed.add_error("collector", &"collector thread panicked");
+                    failed
+                }))
+                .collect()
+        });
+
+        for (cluster, result) in self.clusters.iter().zip(results) {
+            collection.merge(cluster.name.as_deref(), result);
+        }
+
+        collection
+    }
+
+    /// Logs out the open TSM and REST API sessions and closes the
+    /// repository connections.
+    pub fn logout(&self) {
+        for (cluster, sessions) in self.clusters.iter().zip(&self.sessions) {
+            let label = cluster.name.as_ref().map(|n| std::format!(" ({})", n)).unwrap_or_default();
+            if let Err(e) = sessions.tsm.logout() {
+                eprintln!("logout error{}: {}", label, e);
+            }
+            if let Err(e) = sessions.rest.sign_out() {
+                eprintln!("sign out error{}: {}", label, e);
+            }
+            if let Err(e) = sessions.repository.close() {
+                eprintln!("repository close error{}: {}", label, e);
+            }
+        }
+    }
+}
+
+fn build_agent() -> Agent {
     let mut tls_config = rustls::ClientConfig::new();
     tls_config
         .dangerous()
         .set_certificate_verifier(Arc::new(crate::tls::NoCertificateVerification {}));
 
-    let agent: Agent = AgentBuilder::new()
+    AgentBuilder::new()
         .timeout_read(Duration::from_secs(5))
         .timeout_write(Duration::from_secs(5))
         .tls_config(Arc::new(tls_config))
-        .build();
-
-    for _ in std::io::stdin().lock().lines() {
-        if checks.eq("all") || checks.eq("tsm") {
-            if let Err(e) = check_tsm_nodes(&agent, &args) {
-                println!("tableau_tsm_status,node=all,service=all,instance=all status_code=3i,\
-                status=\"Unavailable\",requested_deployment_state=\"Unknown\" {}",
-                         get_epoch_nanos());
-                eprintln!("check_tsm_nodes error: {}", e.to_string());
-            }
+        .build()
+}
+
+/// Renders the collections in the configured format to the standard output
+/// or to the output file, and pushes them to the configured exporters.
+struct Output {
+    format: OutputFormat,
+    file: Option<std::fs::File>,
+    exporters: Vec<Box<dyn Exporter>>,
+}
+
+impl Output {
+    fn from_args(collector: &Collector, args: &ArgMatches) -> Self {
+        let file = args.value_of("output_file").map(|path| {
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .unwrap_or_else(|e| {
+                    eprintln!("Cannot open output file {}: {}", path, e);
+                    std::process::exit(2);
+                })
+        });
+
+        Output {
+            format: args.value_of_t("output_format").unwrap_or_else(|e| e.exit()),
+            file,
+            exporters: build_exporters(&collector.agent, args, &collector.clusters),
         }
+    }
 
-        if checks.eq("all") || checks.eq("systeminfo") {
-            if let Err(e) = check_system_info(&agent, hostname) {
-                println!("tableau_systeminfo,worker=all status_code=3i,status=\"Unavailable\" {}"
-                         , get_epoch_nanos());
-                eprintln!("check_system_info error: {}", e.to_string());
+    fn emit(&mut self, collection: &Collection) {
+        export(&mut self.exporters, collection);
+
+        let result = match self.file.as_mut() {
+            Some(file) => write_collection(file, self.format, collection).and_then(|_| file.flush()),
+            None => {
+                let stdout = std::io::stdout();
+                let mut out = stdout.lock();
+                write_collection(&mut out, self.format, collection).and_then(|_| out.flush())
             }
+        };
+
+        if let Err(e) = result {
+            eprintln!("cannot write metrics: {}", e);
+        }
+    }
+}
+
+/// Collects once for every item of `ticks`: a line on stdin sent by
+/// Telegraf, or a timer tick in the self-scheduled mode.
+fn run_collection_loop<I: Iterator>(collector: &Collector, args: &ArgMatches, ticks: I) {
+    let mut output = Output::from_args(collector, args);
+
+    for _ in ticks {
+        output.emit(&collector.collect());
+    }
+}
+
+pub fn run(args: &ArgMatches) {
+    #[cfg(feature = "setuid")]
+    change_current_uid();
+
+    let collector = Arc::new(Collector::from_args(args));
+
+    // Telegraf stops execd plugins with SIGTERM
+    let handler_collector = collector.clone();
+    let handler = ctrlc::set_handler(move || {
+        handler_collector.logout();
+        std::process::exit(0);
+    });
+    if let Err(e) = handler {
+        eprintln!("Cannot install the termination handler: {}", e);
+    }
+
+    match args.subcommand() {
+        Some(("serve", serve_args)) => {
+            let listen = serve_args.value_of("listen").expect("Listen address must be defined");
+            let cache_ttl: u64 = serve_args.value_of_t("cache_ttl").unwrap_or_else(|e| e.exit());
+            server::serve(&collector, listen, Duration::from_secs(cache_ttl));
+        }
+        Some(("zabbix-discovery", _)) => {
+            println!("{}", zabbix::get_discovery(&collector.collect()));
         }
+        Some(("check", _)) => {
+            let (state, output) = nagios::evaluate(&collector.collect());
+            collector.logout();
+            println!("{}", output);
+            std::process::exit(state.exit_code());
+        }
+        _ => match args.value_of("interval") {
+            Some(interval) => {
+                let interval = parse_duration(interval).expect("interval is validated");
+                if interval.as_millis() == 0 {
+                    eprintln!("interval must be greater than zero");
+                    std::process::exit(2);
+                }
+                let jitter = parse_duration(args.value_of("jitter").unwrap_or("0s")).expect("jitter is validated");
+                run_collection_loop(&collector, args, schedule::Ticker::new(interval, jitter));
+            }
+            None => run_collection_loop(&collector, args, std::io::stdin().lock().lines()),
+        },
+    }
+
+    collector.logout();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `GET api/0.5/status` as documented for Tableau Server 2019.1+.
+    const STATUS_0_5: &str = r#"{
+      "clusterStatus": {
+        "href": "/api/0.5/status",
+        "nodes": [{
+          "nodeId": "node1",
+          "rollupStatus": "Running",
+          "rollupRequestedDeploymentState": "Enabled",
+          "services": [{
+            "serviceName": "vizqlserver",
+            "rollupStatus": "Running",
+            "rollupRequestedDeploymentState": "Enabled",
+            "instances": [{
+              "timestampUtc": 1561137627000,
+              "currentDeploymentState": "Enabled",
+              "instanceId": "0",
+              "binaryVersion": "20191.19.0612.1421",
+              "processStatus": "Active",
+              "code": "ACTIVE",
+              "message": "Active"
+            }]
+          }]
+        }],
+        "rollupStatus": "Running",
+        "rollupRequestedDeploymentState": "Enabled"
+      }
+    }"#;
+
+    #[test]
+    fn status_0_5_is_parsed() {
+        let status: ClusterStatus = serde_json::from_str(STATUS_0_5).unwrap();
+        let cluster = &status.cluster_status;
+        assert_eq!(cluster.rollup_status, "Running");
+
+        let instance = &cluster.nodes[0].services[0].instances[0];
+        assert_eq!(cluster.nodes[0].node_id, "node1");
+        assert_eq!(cluster.nodes[0].services[0].service_name, "vizqlserver");
+        assert_eq!(instance.process_status, "Active");
+        assert_eq!(instance.instance_id, "0");
+        assert_eq!(instance.timestamp_utc, 1_561_137_627_000);
+        assert_eq!(instance.current_deployment_state, "Enabled");
+    }
+
+    #[test]
+    fn status_tolerates_missing_and_differently_typed_fields() {
+        let status: ClusterStatus = serde_json::from_str(r#"{
+          "clusterStatus": {
+            "nodes": [{
+              "nodeId": 1,
+              "services": [{
+                "serviceName": "backgrounder",
+                "instances": [{ "instanceId": 0, "timestampUtc": "1561137627000" }]
+              }]
+            }]
+          }
+        }"#).unwrap();
+        let cluster = &status.cluster_status;
+        assert_eq!(cluster.rollup_status, "Unknown");
+
+        let instance = &cluster.nodes[0].services[0].instances[0];
+        assert_eq!(cluster.nodes[0].node_id, "1");
+        assert_eq!(cluster.nodes[0].rollup_status, "Unknown");
+        assert_eq!(instance.instance_id, "0");
+        assert_eq!(instance.process_status, "Unknown");
+        assert_eq!(instance.timestamp_utc, 1_561_137_627_000);
+        assert_eq!(instance.current_deployment_state, "Unknown");
     }
 }
@@ -0,0 +1,264 @@
+//! License expiry and capacity check using the TSM licensing API.
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::datetime::{days_from_civil, MILLIS_PER_DAY};
+use crate::error::CheckError;
+use crate::hash::fnv1a;
+use crate::metric::{get_epoch_millis, Collection, Metric};
+use crate::tsm::TsmClient;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ProductKeys {
+    product_keys: ProductKeyItems,
+}
+
+#[derive(Deserialize)]
+struct ProductKeyItems {
+    items: Vec<ProductKey>,
+}
+
+/// A license as returned by the TSM API. Usage is only reported by some
+/// versions, hence the optional fields.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ProductKey {
+    serial: String,
+    #[serde(default)]
+    product: Option<String>,
+    #[serde(default)]
+    is_valid: Option<bool>,
+    #[serde(default)]
+    is_active: Option<bool>,
+    #[serde(rename = "expiration", default)]
+    expires_at: Option<Value>,
+    #[serde(rename = "maintenanceExpiration", default)]
+    maintenance_expires_at: Option<Value>,
+    #[serde(rename = "numCores", default)]
+    licensed_cores: Option<i64>,
+    #[serde(rename = "numUsers", default)]
+    licensed_users: Option<i64>,
+    #[serde(default)]
+    used_cores: Option<i64>,
+    #[serde(default)]
+    used_users: Option<i64>,
+}
+
+/// Accepts epoch milliseconds or a date string starting with `YYYY-MM-DD`.
+fn parse_date_millis(value: &Value) -> Option<i64> {
+    match value {
+        Value::Number(n) => n.as_i64(),
+        Value::String(s) if s.len() >= 10 => {
+            let year = s.get(0..4)?.parse().ok()?;
+            let month = s.get(5..7)?.parse().ok()?;
+            let day = s.get(8..10)?.parse().ok()?;
+            Some(days_from_civil(year, month, day) * MILLIS_PER_DAY)
+        }
+        _ => None,
+    }
+}
+
+/// Calendar days from `now` to the date, so a license is not reported as
+/// expired on its last day.
+fn get_days_to(value: &Option<Value>, now: i64) -> Option<i64> {
+    value.as_ref()
+        .and_then(parse_date_millis)
+        .map(|at| at.div_euclid(MILLIS_PER_DAY) - now.div_euclid(MILLIS_PER_DAY))
+}
+
+/// Keeps the first and last group of a product key and adds a short hash of
+/// the whole key, so keys sharing those groups can still be told apart
+/// without exposing them.
+fn mask_product_key(serial: &str) -> String {
+    let hash = fnv1a(serial.bytes()) & 0xff_ffff;
+    let groups: Vec<&str> = serial.split('-').collect();
+    match groups.as_slice() {
+        [first, .., last] if groups.len() > 2 => std::format!("{}-****-{}-{:06x}", first, last, hash),
+        _ => std::format!("****-{:06x}", hash),
+    }
+}
+
+/// Status code and status of a license: expired or invalid licenses are
+/// errors, licenses expiring within `warning_days` are warnings.
+fn get_license_status(key: &ProductKey, days_to_expiry: Option<i64>, warning_days: i64) -> (i64, &'static str) {
+    match days_to_expiry {
+        _ if key.is_valid == Some(false) => (2, "Invalid"),
+        Some(days) if days < 0 => (2, "Expired"),
+        Some(days) if days <= warning_days => (1, "Expiring"),
+        _ => (0, "Valid"),
+    }
+}
+
+pub fn check_licensing(tsm: &TsmClient, warning_days: i64, collection: &mut Collection) -> Result<(), CheckError> {
+    let keys: ProductKeys = tsm.get_json("licensing/productKeys")?;
+    let now = get_epoch_millis() as i64;
+
+    let mut worst = (0, "Valid");
+    let mut min_days_to_expiry: Option<i64> = None;
+    let (mut licensed_cores, mut licensed_users) = (0, 0);
+    let (mut used_cores, mut used_users): (Option<i64>, Option<i64>) = (None, None);
+    let mut details = Vec::new();
+
+    for key in &keys.product_keys.items {
+        let days_to_expiry = get_days_to(&key.expires_at, now);
+        let maintenance_days_to_expiry = get_days_to(&key.maintenance_expires_at, now);
+        let (status_code, status) = get_license_status(key, days_to_expiry, warning_days);
+        let product_key = mask_product_key(&key.serial);
+
+        let mut metric = Metric::new("tableau_license")
+            .tag("product_key", &product_key)
+            .tag("product", key.product.as_deref().unwrap_or("Unknown"))
+            .field("status_code", status_code)
+            .field("status", status);
+        if let Some(days) = days_to_expiry {
+            metric = metric.field("days_to_expiry", days);
+        }
+        if let Some(days) = maintenance_days_to_expiry {
+            metric = metric.field("maintenance_days_to_expiry", days);
+        }
+        for (field, value) in &[("licensed_cores", key.licensed_cores), ("licensed_users", key.licensed_users),
+                                ("used_cores", key.used_cores), ("used_users", key.used_users)] {
+            if let Some(value) = value {
+                metric = metric.field(field, *value);
+            }
+        }
+        if let Some(active) = key.is_active {
+            metric = metric.field("active", active);
+        }
+        collection.push(metric);
+
+        details.push(serde_json::json!({
+            "productKey": product_key,
+            "product": key.product,
+            "status": status,
+            "daysToExpiry": days_to_expiry,
+            "maintenanceDaysToExpiry": maintenance_days_to_expiry,
+            "licensedCores": key.licensed_cores,
+            "licensedUsers": key.licensed_users,
+            "usedCores": key.used_cores,
+            "usedUsers": key.used_users,
+        }));
+
+        if status_code > worst.0 {
+            worst = (status_code, status);
+        }
+        if let Some(days) = days_to_expiry {
+            min_days_to_expiry = Some(min_days_to_expiry.map_or(days, |min| min.min(days)));
+        }
+        licensed_cores += key.licensed_cores.unwrap_or(0);
+        licensed_users += key.licensed_users.unwrap_or(0);
+        if let Some(cores) = key.used_cores {
+            used_cores = Some(used_cores.unwrap_or(0) + cores);
+        }
+        if let Some(users) = key.used_users {
+            used_users = Some(used_users.unwrap_or(0) + users);
+        }
+    }
+
+    let mut summary = Metric::new("tableau_license")
+        .tag("product_key", "all")
+        .tag("product", "all")
+        .field("status_code", worst.0)
+        .field("status", worst.1)
+        .field("licensed_cores", licensed_cores)
+        .field("licensed_users", licensed_users);
+    // usage is only totalled when the server reports it
+    for (field, value) in &[("used_cores", used_cores), ("used_users", used_users)] {
+        if let Some(value) = value {
+            summary = summary.field(field, *value);
+        }
+    }
+    if let Some(days) = min_days_to_expiry {
+        summary = summary.field("days_to_expiry", days);
+    }
+    collection.push(summary);
+
+    collection.set_detail("licensing", Value::from(details));
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `GET api/0.5/licensing/productKeys` with a core and a user based license.
+    const PRODUCT_KEYS_0_5: &str = r#"{
+      "productKeys": {
+        "items": [{
+          "serial": "TSAB-1234-5678-9ABC-DEF0",
+          "product": "Tableau Server Core",
+          "isValid": true,
+          "isActive": true,
+          "expiration": "2026-12-31",
+          "maintenanceExpiration": "2026-12-31",
+          "numCores": 16
+        }, {
+          "serial": "TSXX-0000-1111-2222-3333",
+          "product": "Tableau Server Viewer",
+          "isValid": false,
+          "isActive": true,
+          "expiration": 1798675200000,
+          "numUsers": 100
+        }]
+      }
+    }"#;
+
+    /// 2026-12-31T00:00:00Z
+    const EXPIRY_DAY: i64 = 1_798_675_200_000;
+
+    #[test]
+    fn product_keys_0_5_are_parsed() {
+        let keys: ProductKeys = serde_json::from_str(PRODUCT_KEYS_0_5).unwrap();
+        assert_eq!(keys.product_keys.items.len(), 2);
+        let (core, viewer) = (&keys.product_keys.items[0], &keys.product_keys.items[1]);
+
+        assert_eq!(core.product.as_deref(), Some("Tableau Server Core"));
+        assert_eq!((core.licensed_cores, core.licensed_users, core.used_cores), (Some(16), None, None));
+        assert_eq!(get_days_to(&core.expires_at, EXPIRY_DAY), Some(0));
+        assert_eq!(get_days_to(&core.maintenance_expires_at, EXPIRY_DAY), Some(0));
+        assert_eq!(get_days_to(&viewer.expires_at, EXPIRY_DAY), Some(0));
+        assert_eq!(viewer.licensed_users, Some(100));
+        assert_eq!(viewer.is_valid, Some(false));
+    }
+
+    #[test]
+    fn days_to_expiry_count_calendar_days() {
+        let date = Some(Value::from("2026-12-31"));
+        let hour = 3_600_000;
+
+        assert_eq!(get_days_to(&date, EXPIRY_DAY - 30 * MILLIS_PER_DAY), Some(30));
+        assert_eq!(get_days_to(&date, EXPIRY_DAY - hour), Some(1));
+        assert_eq!(get_days_to(&date, EXPIRY_DAY + 23 * hour), Some(0));
+        assert_eq!(get_days_to(&date, EXPIRY_DAY + 25 * hour), Some(-1));
+        assert_eq!(get_days_to(&Some(Value::from("never")), EXPIRY_DAY), None);
+        assert_eq!(get_days_to(&None, EXPIRY_DAY), None);
+    }
+
+    #[test]
+    fn license_status_follows_expiry_and_validity() {
+        let keys: ProductKeys = serde_json::from_str(PRODUCT_KEYS_0_5).unwrap();
+        let (valid, invalid) = (&keys.product_keys.items[0], &keys.product_keys.items[1]);
+
+        assert_eq!(get_license_status(valid, Some(31), 30), (0, "Valid"));
+        assert_eq!(get_license_status(valid, Some(30), 30), (1, "Expiring"));
+        assert_eq!(get_license_status(valid, Some(0), 30), (1, "Expiring"));
+        assert_eq!(get_license_status(valid, Some(-1), 30), (2, "Expired"));
+        assert_eq!(get_license_status(valid, None, 30), (0, "Valid"));
+        assert_eq!(get_license_status(invalid, Some(100), 30), (2, "Invalid"));
+    }
+
+    #[test]
+    fn masked_product_keys_are_distinct() {
+        let first = mask_product_key("TSAB-1234-5678-9ABC-DEF0");
+        let second = mask_product_key("TSAB-4321-8765-CBA9-DEF0");
+
+        assert!(first.starts_with("TSAB-****-DEF0-"));
+        assert!(!first.contains("1234"));
+        assert_ne!(first, second);
+        assert_eq!(first, mask_product_key("TSAB-1234-5678-9ABC-DEF0"));
+        assert_ne!(mask_product_key("TSAB1234"), mask_product_key("TSAB4321"));
+    }
+}
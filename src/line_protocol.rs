@@ -0,0 +1,219 @@
+//! InfluxDB line protocol writer.
+//!
+//! Escaping follows the line protocol specification and the Telegraf
+//! serializer: measurement names escape commas and spaces, tag keys, tag
+//! values and field keys additionally escape equal signs, and string field
+//! values escape double quotes and backslashes. Control characters are
+//! written as escape sequences everywhere so a metric always stays on a
+//! single line.
+
+use std::io::Write;
+
+use crate::metric::{FieldValue, Metric};
+
+fn escape_control(c: char, out: &mut String) -> bool {
+    match c {
+        '\n' => out.push_str("\\n"),
+        '\r' => out.push_str("\\r"),
+        '\t' => out.push_str("\\t"),
+        '\u{c}' => out.push_str("\\f"),
+        _ => return false,
+    }
+    true
+}
+
+pub fn escape_measurement(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    for c in name.chars() {
+        if escape_control(c, &mut out) {
+            continue;
+        }
+        if c == ',' || c == ' ' {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Escapes tag keys, tag values and field keys.
+pub fn escape_key(key: &str) -> String {
+    let mut out = String::with_capacity(key.len());
+    for c in key.chars() {
+        if escape_control(c, &mut out) {
+            continue;
+        }
+        if c == ',' || c == ' ' || c == '=' {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    // a trailing backslash would escape the following separator
+    if out.ends_with('\\') && !out.ends_with("\\\\") {
+        out.push('\\');
+    }
+    out
+}
+
+pub fn escape_string_field(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        if escape_control(c, &mut out) {
+            continue;
+        }
+        if c == '"' || c == '\\' {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out.push('"');
+    out
+}
+
+fn format_field(value: &FieldValue) -> Option<String> {
+    match value {
+        FieldValue::Integer(i) => Some(std::format!("{}i", i)),
+        FieldValue::Float(f) if f.is_finite() => Some(f.to_string()),
+        FieldValue::Float(_) => None,
+        FieldValue::Str(s) => Some(escape_string_field(s)),
+        FieldValue::Boolean(b) => Some(b.to_string()),
+    }
+}
+
+/// Formats a metric as a single line. Returns `None` when the metric has no
+/// field that can be represented in line protocol.
+pub fn format_metric(metric: &Metric) -> Option<String> {
+    let mut line = escape_measurement(&metric.measurement);
+
+    // empty tag values are not allowed by the protocol
+    for (key, value) in metric.tags.iter().filter(|(_, v)| !v.is_empty()) {
+        line.push(',');
+        line.push_str(&escape_key(key));
+        line.push('=');
+        line.push_str(&escape_key(value));
+    }
+
+    let fields: Vec<String> = metric.fields.iter()
+        .filter_map(|(key, value)| {
+            format_field(value).map(|v| std::format!("{}={}", escape_key(key), v))
+        })
+        .collect();
+
+    if fields.is_empty() {
+        return None;
+    }
+
+    Some(std::format!("{} {} {}", line, fields.join(","), metric.timestamp))
+}
+
+pub fn write_metrics(out: &mut dyn Write, metrics: &[Metric]) -> std::io::Result<()> {
+    for line in metrics.iter().filter_map(format_metric) {
+        writeln!(out, "{}", line)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metric() -> Metric {
+        let mut metric = Metric::new("tableau_tsm_status");
+        metric.timestamp = 1_600_000_000_000_000_000;
+        metric
+    }
+
+    #[test]
+    fn plain_metric_is_unchanged() {
+        let m = metric()
+            .tag("node", "node1")
+            .tag("service", "vizqlserver")
+            .field("status_code", 0i8)
+            .field("status", "Running")
+            .field("elapsed", 1234u128);
+
+        assert_eq!(format_metric(&m).unwrap(),
+                   "tableau_tsm_status,node=node1,service=vizqlserver \
+                   status_code=0i,status=\"Running\",elapsed=1234i 1600000000000000000");
+    }
+
+    #[test]
+    fn tsm_message_with_quotes_commas_and_newlines() {
+        let m = metric()
+            .field("message", "Error opening named pipe \"\\\\.\\pipe\\tab\", retrying.\r\n\
+                              Connection refused: connect");
+
+        assert_eq!(format_metric(&m).unwrap(),
+                   "tableau_tsm_status \
+                   message=\"Error opening named pipe \\\"\\\\\\\\.\\\\pipe\\\\tab\\\", retrying.\\r\\n\
+                   Connection refused: connect\" 1600000000000000000");
+    }
+
+    #[test]
+    fn tag_values_escape_separators() {
+        let m = metric()
+            .tag("process", "vizqlserver")
+            .tag("worker", "node 1:8000,node=2")
+            .field("status_code", 1i8);
+
+        assert_eq!(format_metric(&m).unwrap(),
+                   "tableau_tsm_status,process=vizqlserver,worker=node\\ 1:8000\\,node\\=2 \
+                   status_code=1i 1600000000000000000");
+    }
+
+    #[test]
+    fn tag_value_with_trailing_backslash() {
+        let m = metric()
+            .tag("instance", "C:\\ProgramData\\Tableau\\")
+            .field("status_code", 2i8);
+
+        assert_eq!(format_metric(&m).unwrap(),
+                   "tableau_tsm_status,instance=C:\\ProgramData\\Tableau\\\\ \
+                   status_code=2i 1600000000000000000");
+    }
+
+    #[test]
+    fn multiline_tag_value_stays_on_one_line() {
+        let m = metric()
+            .tag("node", "node1\nnode2")
+            .field("status_code", 0i8);
+
+        let line = format_metric(&m).unwrap();
+        assert_eq!(line, "tableau_tsm_status,node=node1\\nnode2 status_code=0i 1600000000000000000");
+        assert!(!line.contains('\n'));
+    }
+
+    #[test]
+    fn measurement_and_field_keys_are_escaped() {
+        let mut m = Metric::new("tableau tsm,status")
+            .field("status code", 0i8)
+            .field("a=b", true);
+        m.timestamp = 1;
+
+        assert_eq!(format_metric(&m).unwrap(),
+                   "tableau\\ tsm\\,status status\\ code=0i,a\\=b=true 1");
+    }
+
+    #[test]
+    fn empty_tags_and_invalid_floats_are_skipped() {
+        let m = metric()
+            .tag("node", "")
+            .field("ratio", f64::NAN)
+            .field("value", 0.5);
+
+        assert_eq!(format_metric(&m).unwrap(), "tableau_tsm_status value=0.5 1600000000000000000");
+    }
+
+    #[test]
+    fn metric_without_fields_is_dropped() {
+        let m = metric().tag("node", "node1").field("ratio", f64::INFINITY);
+
+        assert_eq!(format_metric(&m), None);
+
+        let mut out = Vec::new();
+        write_metrics(&mut out, &[m]).unwrap();
+        assert!(out.is_empty());
+    }
+}
@@ -1,7 +1,8 @@
 use clap::{crate_authors, crate_version, App, Arg};
+use tableau_monitoring_execd::parse_duration;
 
 
-fn main() {
+fn build_app() -> App<'static> {
     let mut app = App::new("tableau-monitoring-execd")
         .version(crate_version!())
         .author(crate_authors!())
@@ -44,11 +45,262 @@ fn main() {
             .short('c')
             .long("checks")
             .value_name("CHECKS")
-            .about("Username for TSM Authentication")
+            .about("Comma separated list of checks to run")
             .env("TME_CHECKS")
             .takes_value(true)
+            .use_delimiter(true)
+            .require_delimiter(true)
             .default_value("all")
-            .possible_values(&["all", "tsm", "systeminfo"])
+            .possible_values(&["all", "tsm", "systeminfo", "jobs", "licensing", "pending_changes", "backup", "topology", "configuration", "sites", "backgrounder", "repository", "extract_refreshes", "subscriptions"])
+        )
+        .arg(Arg::new("pat_name")
+            .long("pat-name")
+            .value_name("NAME")
+            .about("Personal Access Token name for the REST API checks")
+            .env("TME_PAT_NAME")
+            .takes_value(true)
+        )
+        .arg(Arg::new("pat_secret")
+            .long("pat-secret")
+            .value_name("SECRET")
+            .about("Personal Access Token secret for the REST API checks")
+            .env("TME_PAT_SECRET")
+            .takes_value(true)
+            .conflicts_with("pat_secret_file")
+        )
+        .arg(Arg::new("pat_secret_file")
+            .long("pat-secret-file")
+            .value_name("PATH")
+            .about("File holding the Personal Access Token secret, read at every sign-in")
+            .env("TME_PAT_SECRET_FILE")
+            .takes_value(true)
+        )
+        .arg(Arg::new("site")
+            .long("site")
+            .value_name("CONTENT_URL")
+            .about("Content URL of the site the REST API checks sign in to, empty for the default site")
+            .env("TME_SITE")
+            .takes_value(true)
+        )
+        .arg(Arg::new("repository_host")
+            .long("repository-host")
+            .value_name("HOST")
+            .about("Host of the Tableau repository PostgreSQL for the repository checks")
+            .env("TME_REPOSITORY_HOST")
+            .takes_value(true)
+        )
+        .arg(Arg::new("repository_port")
+            .long("repository-port")
+            .value_name("PORT")
+            .about("Port of the Tableau repository PostgreSQL")
+            .env("TME_REPOSITORY_PORT")
+            .default_value("8060")
+            .takes_value(true)
+        )
+        .arg(Arg::new("repository_user")
+            .long("repository-user")
+            .value_name("USERNAME")
+            .about("Repository user, normally the readonly user")
+            .env("TME_REPOSITORY_USER")
+            .default_value("readonly")
+            .takes_value(true)
+        )
+        .arg(Arg::new("repository_password")
+            .long("repository-password")
+            .value_name("PASSWORD")
+            .about("Password of the repository user")
+            .env("TME_REPOSITORY_PASSWORD")
+            .takes_value(true)
+        )
+        .arg(Arg::new("long_running_job_threshold")
+            .long("long-running-job-threshold")
+            .value_name("DURATION")
+            .about("Report a warning when a background job has been running for longer than this")
+            .env("TME_LONG_RUNNING_JOB_THRESHOLD")
+            .default_value("2h")
+            .takes_value(true)
+            .validator(parse_duration)
+        )
+        .arg(Arg::new("jobs_running_threshold")
+            .long("jobs-running-threshold")
+            .value_name("DURATION")
+            .about("Report an error when a TSM job has been running for longer than this")
+            .env("TME_JOBS_RUNNING_THRESHOLD")
+            .default_value("1h")
+            .takes_value(true)
+            .validator(parse_duration)
+        )
+        .arg(Arg::new("jobs_failure_window")
+            .long("jobs-failure-window")
+            .value_name("DURATION")
+            .about("Report an error when a TSM job failed within this period")
+            .env("TME_JOBS_FAILURE_WINDOW")
+            .default_value("1h")
+            .takes_value(true)
+            .validator(parse_duration)
+        )
+        .arg(Arg::new("cluster_name")
+            .long("cluster-name")
+            .value_name("NAME")
+            .about("Add a cluster tag with this name to every metric")
+            .env("TME_CLUSTER_NAME")
+            .takes_value(true)
+        )
+        .arg(Arg::new("clusters_file")
+            .long("clusters-file")
+            .value_name("PATH")
+            .about("TOML file listing the clusters to monitor, overrides the TSM and systeminfo options")
+            .env("TME_CLUSTERS_FILE")
+            .takes_value(true)
+        )
+        .arg(Arg::new("license_warning_days")
+            .long("license-warning-days")
+            .value_name("DAYS")
+            .about("Report a warning when a license expires within this many days")
+            .env("TME_LICENSE_WARNING_DAYS")
+            .default_value("30")
+            .takes_value(true)
+        )
+        .arg(Arg::new("backup_max_age")
+            .long("backup-max-age")
+            .value_name("DURATION")
+            .about("Report an error when the last successful backup is older than this")
+            .env("TME_BACKUP_MAX_AGE")
+            .default_value("25h")
+            .takes_value(true)
+            .validator(parse_duration)
+        )
+        .arg(Arg::new("output_format")
+            .short('o')
+            .long("output-format")
+            .value_name("FORMAT")
+            .about("Format of the emitted metrics")
+            .env("TME_OUTPUT_FORMAT")
+            .takes_value(true)
+            .default_value("influx")
+            .possible_values(&["influx", "prometheus", "json", "none"])
+        )
+        .arg(Arg::new("output_file")
+            .long("output-file")
+            .value_name("PATH")
+            .about("Append the metrics to this file instead of the standard output")
+            .env("TME_OUTPUT_FILE")
+            .takes_value(true)
+        )
+        .arg(Arg::new("interval")
+            .long("interval")
+            .value_name("DURATION")
+            .about("Collect on an internal timer aligned to the wall clock instead of reading stdin (e.g. 30s, 5m)")
+            .env("TME_INTERVAL")
+            .takes_value(true)
+            .validator(parse_duration)
+        )
+        .arg(Arg::new("jitter")
+            .long("jitter")
+            .value_name("DURATION")
+            .about("Random delay of up to this duration added to every scheduled collection")
+            .env("TME_JITTER")
+            .default_value("0s")
+            .takes_value(true)
+            .validator(parse_duration)
+        )
+        .arg(Arg::new("otlp_endpoint")
+            .long("otlp-endpoint")
+            .value_name("BASEURL")
+            .about("Push metrics to this OTLP/HTTP collector base url (v1/metrics is appended)")
+            .env("TME_OTLP_ENDPOINT")
+            .takes_value(true)
+        )
+        .arg(Arg::new("otlp_headers")
+            .long("otlp-headers")
+            .value_name("KEY=VALUE,...")
+            .about("Additional HTTP headers sent to the OTLP collector")
+            .env("TME_OTLP_HEADERS")
+            .takes_value(true)
+        )
+        .arg(Arg::new("otlp_resource_attributes")
+            .long("otlp-resource-attributes")
+            .value_name("KEY=VALUE,...")
+            .about("Additional resource attributes of the exported OTLP metrics")
+            .env("TME_OTLP_RESOURCE_ATTRIBUTES")
+            .takes_value(true)
+        )
+        .arg(Arg::new("influxdb_url")
+            .long("influxdb-url")
+            .value_name("BASEURL")
+            .about("Write metrics to this InfluxDB v2 server base url")
+            .env("TME_INFLUXDB_URL")
+            .takes_value(true)
+            .requires_all(&["influxdb_org", "influxdb_bucket", "influxdb_token"])
+        )
+        .arg(Arg::new("influxdb_org")
+            .long("influxdb-org")
+            .value_name("ORG")
+            .about("InfluxDB organization")
+            .env("TME_INFLUXDB_ORG")
+            .takes_value(true)
+        )
+        .arg(Arg::new("influxdb_bucket")
+            .long("influxdb-bucket")
+            .value_name("BUCKET")
+            .about("InfluxDB bucket to write to")
+            .env("TME_INFLUXDB_BUCKET")
+            .takes_value(true)
+        )
+        .arg(Arg::new("influxdb_token")
+            .long("influxdb-token")
+            .value_name("TOKEN")
+            .about("InfluxDB API token")
+            .env("TME_INFLUXDB_TOKEN")
+            .takes_value(true)
+        )
+        .arg(Arg::new("influxdb_retries")
+            .long("influxdb-retries")
+            .value_name("COUNT")
+            .about("Number of retries of a failed InfluxDB write")
+            .env("TME_INFLUXDB_RETRIES")
+            .default_value("3")
+            .takes_value(true)
+        )
+        .arg(Arg::new("zabbix_server")
+            .long("zabbix-server")
+            .value_name("HOST[:PORT]")
+            .about("Send metrics to this Zabbix server or proxy using the sender protocol")
+            .env("TME_ZABBIX_SERVER")
+            .takes_value(true)
+            .requires("zabbix_host")
+        )
+        .arg(Arg::new("zabbix_host")
+            .long("zabbix-host")
+            .value_name("HOST")
+            .about("Name of the Zabbix host the sent items belong to")
+            .env("TME_ZABBIX_HOST")
+            .takes_value(true)
+        )
+        .subcommand(App::new("zabbix-discovery")
+            .about("Run the checks once and print the Zabbix low-level discovery JSON")
+        )
+        .subcommand(App::new("check")
+            .about("Run the checks once and report the result as a Nagios/Icinga plugin")
+        )
+        .subcommand(App::new("serve")
+            .about("Serve the collected metrics on /metrics in Prometheus format instead of reading stdin")
+            .arg(Arg::new("listen")
+                .long("listen")
+                .value_name("ADDRESS")
+                .about("Address to listen on")
+                .env("TME_LISTEN")
+                .default_value("0.0.0.0:9678")
+                .takes_value(true)
+            )
+            .arg(Arg::new("cache_ttl")
+                .long("cache-ttl")
+                .value_name("SECONDS")
+                .about("Seconds to reuse the last collection for subsequent scrapes")
+                .env("TME_CACHE_TTL")
+                .default_value("5")
+                .takes_value(true)
+            )
         );
 
     #[cfg(unix)]
@@ -69,5 +321,36 @@ fn main() {
                     .takes_value(true));
         }
 
-    tableau_monitoring_execd::run(&app.get_matches());
+    app
+}
+
+fn main() {
+    tableau_monitoring_execd::run(&build_app().get_matches());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_checks(args: &[&str]) -> (Vec<String>, Option<String>) {
+        let matches = build_app().try_get_matches_from(args).unwrap();
+        let checks = matches.values_of("checks").unwrap().map(str::to_string).collect();
+        (checks, matches.subcommand_name().map(str::to_string))
+    }
+
+    #[test]
+    fn checks_are_followed_by_a_subcommand() {
+        assert_eq!(get_checks(&["tme", "-c", "tsm,jobs", "check"]),
+                   (vec!["tsm".to_string(), "jobs".to_string()], Some("check".to_string())));
+        assert_eq!(get_checks(&["tme", "-c", "tsm", "serve"]),
+                   (vec!["tsm".to_string()], Some("serve".to_string())));
+        assert_eq!(get_checks(&["tme", "--checks", "systeminfo", "zabbix-discovery"]),
+                   (vec!["systeminfo".to_string()], Some("zabbix-discovery".to_string())));
+    }
+
+    #[test]
+    fn checks_default_to_all() {
+        std::env::remove_var("TME_CHECKS");
+        assert_eq!(get_checks(&["tme"]), (vec!["all".to_string()], None));
+    }
 }
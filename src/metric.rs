@@ -0,0 +1,220 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A single field value of a metric.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldValue {
+    Integer(i64),
+    Float(f64),
+    Str(String),
+    Boolean(bool),
+}
+
+impl From<i64> for FieldValue {
+    fn from(value: i64) -> Self {
+        FieldValue::Integer(value)
+    }
+}
+
+impl From<i8> for FieldValue {
+    fn from(value: i8) -> Self {
+        FieldValue::Integer(value.into())
+    }
+}
+
+impl From<u64> for FieldValue {
+    fn from(value: u64) -> Self {
+        FieldValue::Integer(value as i64)
+    }
+}
+
+impl From<u128> for FieldValue {
+    fn from(value: u128) -> Self {
+        FieldValue::Integer(value as i64)
+    }
+}
+
+impl From<f64> for FieldValue {
+    fn from(value: f64) -> Self {
+        FieldValue::Float(value)
+    }
+}
+
+impl From<bool> for FieldValue {
+    fn from(value: bool) -> Self {
+        FieldValue::Boolean(value)
+    }
+}
+
+impl From<&str> for FieldValue {
+    fn from(value: &str) -> Self {
+        FieldValue::Str(value.to_string())
+    }
+}
+
+impl From<String> for FieldValue {
+    fn from(value: String) -> Self {
+        FieldValue::Str(value)
+    }
+}
+
+/// One measurement point produced by a check, independent of the output format.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metric {
+    pub measurement: String,
+    pub tags: Vec<(String, String)>,
+    pub fields: Vec<(String, FieldValue)>,
+    pub timestamp: u128,
+}
+
+impl Metric {
+    pub fn new(measurement: &str) -> Self {
+        Metric {
+            measurement: measurement.to_string(),
+            tags: Vec::new(),
+            fields: Vec::new(),
+            timestamp: get_epoch_nanos(),
+        }
+    }
+
+    pub fn tag(mut self, key: &str, value: &str) -> Self {
+        self.tags.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn field<V: Into<FieldValue>>(mut self, key: &str, value: V) -> Self {
+        self.fields.push((key.to_string(), value.into()));
+        self
+    }
+
+    pub fn get_tag(&self, key: &str) -> Option<&str> {
+        self.tags.iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn get_field(&self, key: &str) -> Option<&FieldValue> {
+        self.fields.iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v)
+    }
+}
+
+pub fn get_epoch_nanos() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos()
+}
+
+pub fn get_epoch_millis() -> u64 {
+    (get_epoch_nanos() / 1_000_000) as u64
+}
+
+/// Everything gathered by the checks during one collection: the flat metrics
+/// for the line based formats, the structured documents of each check for
+/// the JSON format and the errors of the checks that failed.
+#[derive(Debug, Default)]
+pub struct Collection {
+    pub timestamp: u128,
+    pub metrics: Vec<Metric>,
+    pub details: serde_json::Map<String, serde_json::Value>,
+    pub errors: Vec<(String, String)>,
+}
+
+impl Collection {
+    pub fn new() -> Self {
+        Collection {
+            timestamp: get_epoch_nanos(),
+            ..Default::default()
+        }
+    }
+
+    pub fn push(&mut self, metric: Metric) {
+        self.metrics.push(metric);
+    }
+
+    pub fn set_detail(&mut self, check: &str, value: serde_json::Value) {
+        self.details.insert(check.to_string(), value);
+    }
+
+    pub fn add_error(&mut self, check: &str, error: &dyn std::fmt::Display) {
+        self.errors.push((check.to_string(), error.to_string()));
+    }
+
+    /// Adds the collection of a single cluster. Metrics of a named cluster get
+    /// a leading `cluster` tag, its details and errors are nested under
+    /// `clusters.<name>`.
+    pub fn merge(&mut self, cluster: Option<&str>, other: Collection) {
+        let name = match cluster {
+            Some(name) => name,
+            None => {
+                self.metrics.extend(other.metrics);
+                self.details.extend(other.details);
+                self.errors.extend(other.errors);
+                return;
+            }
+        };
+
+        for mut metric in other.metrics {
+            metric.tags.insert(0, ("cluster".to_string(), name.to_string()));
+            self.metrics.push(metric);
+        }
+
+        let mut details = other.details;
+        if !other.errors.is_empty() {
+            let errors = other.errors.iter()
+                .map(|(check, error)| (check.clone(), serde_json::Value::from(error.as_str())))
+                .collect();
+            details.insert("errors".to_string(), serde_json::Value::Object(errors));
+        }
+
+        let clusters = self.details.entry("clusters")
+            .or_insert_with(|| serde_json::Value::Object(serde_json::Map::new()));
+        clusters[name] = serde_json::Value::Object(details);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_collection(check: &str, error: Option<&str>) -> Collection {
+        let mut collection = Collection::new();
+        collection.push(Metric::new("tableau_process").tag("node", "node1").field("status_code", 0i64));
+        collection.set_detail(check, serde_json::json!({ "status": "Running" }));
+        if let Some(error) = error {
+            collection.add_error("jobs", &error);
+        }
+        collection
+    }
+
+    #[test]
+    fn unnamed_cluster_is_merged_as_is() {
+        let mut collection = Collection::new();
+        collection.merge(None, get_collection("tsm", Some("timed out")));
+
+        assert_eq!(collection.metrics[0].tags, vec![("node".to_string(), "node1".to_string())]);
+        assert_eq!(collection.details["tsm"], serde_json::json!({ "status": "Running" }));
+        assert!(!collection.details.contains_key("clusters"));
+        assert_eq!(collection.errors, vec![("jobs".to_string(), "timed out".to_string())]);
+    }
+
+    #[test]
+    fn named_clusters_are_tagged_and_nested() {
+        let mut collection = Collection::new();
+        collection.merge(Some("prod"), get_collection("tsm", None));
+        collection.merge(Some("test"), get_collection("tsm", Some("timed out")));
+
+        assert_eq!(collection.metrics.len(), 2);
+        assert_eq!(collection.metrics[0].tags[0], ("cluster".to_string(), "prod".to_string()));
+        assert_eq!(collection.metrics[1].tags[0], ("cluster".to_string(), "test".to_string()));
+        assert_eq!(collection.metrics[1].get_tag("node"), Some("node1"));
+
+        assert_eq!(collection.details["clusters"], serde_json::json!({
+            "prod": { "tsm": { "status": "Running" } },
+            "test": { "tsm": { "status": "Running" }, "errors": { "jobs": "timed out" } },
+        }));
+        // the errors of a named cluster only show up in its details
+        assert!(collection.errors.is_empty());
+    }
+}
@@ -0,0 +1,265 @@
+//! Nagios/Icinga plugin output.
+
+use crate::metric::{Collection, FieldValue, Metric};
+
+/// Maximum number of problems listed in the status line.
+const MAX_LISTED_PROBLEMS: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PluginState {
+    Ok,
+    Warning,
+    Critical,
+    Unknown,
+}
+
+impl PluginState {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            PluginState::Ok => 0,
+            PluginState::Warning => 1,
+            PluginState::Critical => 2,
+            PluginState::Unknown => 3,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            PluginState::Ok => "OK",
+            PluginState::Warning => "WARNING",
+            PluginState::Critical => "CRITICAL",
+            PluginState::Unknown => "UNKNOWN",
+        }
+    }
+
+    /// Severity used to pick the worst state: an unknown result is worse
+    /// than a warning but better than a confirmed failure.
+    fn severity(self) -> u8 {
+        match self {
+            PluginState::Ok => 0,
+            PluginState::Warning => 1,
+            PluginState::Unknown => 2,
+            PluginState::Critical => 3,
+        }
+    }
+
+    /// Maps a `status_code` field: disabled (-1) services are not a problem.
+    fn from_status_code(code: i64) -> Self {
+        match code {
+            -1 | 0 => PluginState::Ok,
+            1 => PluginState::Warning,
+            3 => PluginState::Unknown,
+            _ => PluginState::Critical,
+        }
+    }
+}
+
+fn get_status_code(metric: &Metric) -> Option<i64> {
+    match metric.get_field("status_code") {
+        Some(FieldValue::Integer(code)) => Some(*code),
+        _ => None,
+    }
+}
+
+fn get_label(metric: &Metric) -> String {
+    let name = metric.measurement.trim_start_matches("tableau_");
+    let tags: Vec<&str> = metric.tags.iter()
+        .map(|(_, v)| v.as_str())
+        .filter(|v| *v != "all")
+        .collect();
+
+    if tags.is_empty() {
+        name.to_string()
+    } else {
+        std::format!("{} {}", name, tags.join("/"))
+    }
+}
+
+/// Label of the elapsed time of a check, prefixed with the cluster so the
+/// labels stay unique with several clusters. Labels with spaces, `=` or `'`
+/// are quoted as the plugin guidelines require.
+fn get_elapsed_label(metric: &Metric) -> String {
+    let name = metric.measurement.trim_start_matches("tableau_");
+    let label = match metric.get_tag("cluster") {
+        Some(cluster) => std::format!("{}_{}_elapsed", cluster, name),
+        None => std::format!("{}_elapsed", name),
+    };
+
+    if label.contains(&[' ', '=', '\''][..]) {
+        std::format!("'{}'", label.replace('\'', "''"))
+    } else {
+        label
+    }
+}
+
+fn get_perfdata(collection: &Collection, counts: &[(&str, usize)]) -> String {
+    let mut perfdata: Vec<String> = counts.iter()
+        .map(|(label, count)| std::format!("{}={}", label, count))
+        .collect();
+
+    for metric in &collection.metrics {
+        if let Some(FieldValue::Integer(elapsed)) = metric.get_field("elapsed") {
+            perfdata.push(std::format!("{}={}us", get_elapsed_label(metric), elapsed));
+        }
+    }
+
+    perfdata.join(" ")
+}
+
+/// Evaluates every metric carrying a `status_code` and returns the worst
+/// state together with the plugin output line.
+pub fn evaluate(collection: &Collection) -> (PluginState, String) {
+    let mut worst = PluginState::Ok;
+    let mut problems = Vec::new();
+    let (mut ok, mut warning, mut critical, mut unknown, mut disabled) = (0, 0, 0, 0, 0);
+
+    for metric in &collection.metrics {
+        let code = match get_status_code(metric) {
+            Some(code) => code,
+            None => continue,
+        };
+        let state = PluginState::from_status_code(code);
+
+        match (code, state) {
+            (-1, _) => disabled += 1,
+            (_, PluginState::Ok) => ok += 1,
+            (_, PluginState::Warning) => warning += 1,
+            (_, PluginState::Critical) => critical += 1,
+            (_, PluginState::Unknown) => unknown += 1,
+        }
+
+        if state != PluginState::Ok {
+            let status = match metric.get_field("status") {
+                Some(FieldValue::Str(status)) => status.as_str(),
+                _ => "Unknown",
+            };
+            problems.push((state, std::format!("{} {}", get_label(metric), status)));
+        }
+
+        if state.severity() > worst.severity() {
+            worst = state;
+        }
+    }
+
+    if ok + warning + critical + unknown + disabled == 0 {
+        worst = PluginState::Unknown;
+    }
+
+    problems.sort_by_key(|(state, _)| std::cmp::Reverse(state.severity()));
+
+    let summary = if problems.is_empty() {
+        std::format!("{} components running", ok)
+    } else {
+        let mut listed: Vec<String> = problems.iter()
+            .take(MAX_LISTED_PROBLEMS)
+            .map(|(_, p)| p.clone())
+            .collect();
+        if problems.len() > MAX_LISTED_PROBLEMS {
+            listed.push(std::format!("and {} more", problems.len() - MAX_LISTED_PROBLEMS));
+        }
+        listed.join(", ")
+    };
+
+    let perfdata = get_perfdata(collection, &[
+        ("ok", ok), ("warning", warning), ("critical", critical),
+        ("unknown", unknown), ("disabled", disabled),
+    ]);
+
+    (worst, std::format!("TABLEAU {} - {} | {}", worst.label(), summary, perfdata))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(measurement: &str, node: &str, status_code: i64, status: &str) -> Metric {
+        Metric::new(measurement)
+            .tag("node", node)
+            .field("status_code", status_code)
+            .field("status", status)
+    }
+
+    fn collection(metrics: Vec<Metric>) -> Collection {
+        let mut collection = Collection::new();
+        for metric in metrics {
+            collection.push(metric);
+        }
+        collection
+    }
+
+    #[test]
+    fn status_codes_map_to_plugin_states() {
+        let states: Vec<_> = [-1, 0, 1, 2, 3, 4].iter()
+            .map(|code| PluginState::from_status_code(*code).exit_code())
+            .collect();
+        assert_eq!(states, vec![0, 0, 1, 2, 3, 2]);
+    }
+
+    #[test]
+    fn empty_collection_is_unknown() {
+        let (state, output) = evaluate(&Collection::new());
+        assert_eq!(state, PluginState::Unknown);
+        assert!(output.starts_with("TABLEAU UNKNOWN - 0 components running |"), "{}", output);
+    }
+
+    #[test]
+    fn metrics_without_status_code_are_ignored() {
+        let (state, _) = evaluate(&collection(vec![
+            status("tableau_tsm_status", "node1", 0, "Running"),
+            Metric::new("tableau_extract_refresh_failure").field("message", "failed"),
+        ]));
+        assert_eq!(state, PluginState::Ok);
+    }
+
+    #[test]
+    fn disabled_services_are_ok() {
+        let (state, output) = evaluate(&collection(vec![
+            status("tableau_tsm_status", "node1", 0, "Running"),
+            status("tableau_tsm_status", "node2", -1, "Disabled"),
+        ]));
+        assert_eq!(state, PluginState::Ok);
+        assert_eq!(output, "TABLEAU OK - 1 components running | \
+                            ok=1 warning=0 critical=0 unknown=0 disabled=1");
+    }
+
+    #[test]
+    fn worst_state_wins_and_critical_outranks_unknown() {
+        let warning = status("tableau_license", "node1", 1, "Expiring");
+        let unknown = status("tableau_tsm_status", "node1", 3, "Unavailable");
+        let critical = status("tableau_tsm_status", "node2", 2, "Degraded");
+
+        let (state, _) = evaluate(&collection(vec![warning.clone(), status("tableau_tsm_status", "node1", 0, "Running")]));
+        assert_eq!(state, PluginState::Warning);
+
+        let (state, _) = evaluate(&collection(vec![unknown.clone(), warning.clone()]));
+        assert_eq!(state, PluginState::Unknown);
+
+        let (state, output) = evaluate(&collection(vec![warning, critical, unknown]));
+        assert_eq!(state, PluginState::Critical);
+        assert_eq!(output, "TABLEAU CRITICAL - tsm_status node2 Degraded, tsm_status node1 Unavailable, \
+                            license node1 Expiring | ok=0 warning=1 critical=1 unknown=1 disabled=0");
+    }
+
+    #[test]
+    fn problem_list_is_truncated() {
+        let metrics = (0..7).map(|i| status("tableau_tsm_status", &std::format!("node{}", i), 2, "Down")).collect();
+        let (_, output) = evaluate(&collection(metrics));
+        assert!(output.contains("node4 Down, and 2 more |"), "{}", output);
+    }
+
+    #[test]
+    fn elapsed_labels_are_unique_per_cluster() {
+        let mut all = Collection::new();
+        for name in &["prod", "dr site"] {
+            let mut cluster = Collection::new();
+            cluster.push(status("tableau_tsm_status", "node1", 0, "Running"));
+            cluster.push(Metric::new("tableau_tsm_status").tag("node", "all").field("elapsed", 1200i64));
+            all.merge(Some(name), cluster);
+        }
+        all.push(Metric::new("tableau_systeminfo").field("elapsed", 800i64));
+
+        let (_, output) = evaluate(&all);
+        assert!(output.ends_with("disabled=0 prod_tsm_status_elapsed=1200us \
+                                  'dr site_tsm_status_elapsed'=1200us systeminfo_elapsed=800us"), "{}", output);
+    }
+}
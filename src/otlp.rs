@@ -0,0 +1,254 @@
+//! OTLP/HTTP metrics exporter using the JSON encoding of the OTLP protocol.
+
+use std::error::Error;
+
+use serde_json::{json, Value};
+use ureq::Agent;
+
+use crate::gauge::{get_help, to_gauge_families, GaugeValue};
+use crate::metric::{Collection, Metric};
+use crate::Exporter;
+
+/// The resource of the metrics of one cluster, identified by the value of
+/// the `cluster` tag, `None` for a cluster without a name.
+pub struct ClusterResource {
+    pub name: Option<String>,
+    pub attributes: Vec<(String, String)>,
+}
+
+pub struct OtlpExporter {
+    agent: Agent,
+    url: String,
+    headers: Vec<(String, String)>,
+    /// Attributes of every resource, after those of the cluster.
+    resource_attributes: Vec<(String, String)>,
+    clusters: Vec<ClusterResource>,
+}
+
+fn to_attributes(pairs: &[(String, String)]) -> Value {
+    pairs.iter()
+        .map(|(k, v)| json!({ "key": k, "value": { "stringValue": v } }))
+        .collect()
+}
+
+/// Parses `key1=value1,key2=value2` lists as used by `OTEL_EXPORTER_OTLP_HEADERS`.
+pub fn parse_key_value_list(list: &str) -> Vec<(String, String)> {
+    list.split(',')
+        .filter_map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(k), Some(v)) if !k.trim().is_empty() => Some((k.trim().to_string(), v.trim().to_string())),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+impl OtlpExporter {
+    /// `endpoint` is the base URL of the collector, `v1/metrics` is appended to it.
+    pub fn new(agent: &Agent, endpoint: &str, headers: Vec<(String, String)>,
+               resource_attributes: Vec<(String, String)>, clusters: Vec<ClusterResource>) -> Self {
+        OtlpExporter {
+            agent: agent.clone(),
+            url: std::format!("{}v1/metrics", endpoint),
+            headers,
+            resource_attributes,
+            clusters,
+        }
+    }
+
+    /// One `resourceMetrics` entry per cluster. The cluster is an attribute
+    /// of the resource, so the `cluster` tag is left out of the data points.
+    fn to_request(&self, collection: &Collection) -> Value {
+        let resource_metrics: Vec<Value> = self.clusters.iter()
+            .filter_map(|cluster| {
+                let metrics: Vec<Metric> = collection.metrics.iter()
+                    .filter(|metric| metric.get_tag("cluster") == cluster.name.as_deref())
+                    .map(|metric| {
+                        let mut metric = metric.clone();
+                        metric.tags.retain(|(key, _)| key != "cluster");
+                        metric
+                    })
+                    .collect();
+                if metrics.is_empty() {
+                    return None;
+                }
+
+                let mut attributes = cluster.attributes.clone();
+                attributes.extend(self.resource_attributes.iter().cloned());
+                Some(to_resource_metrics(&attributes, &metrics))
+            })
+            .collect();
+
+        json!({ "resourceMetrics": resource_metrics })
+    }
+}
+
+fn to_resource_metrics(attributes: &[(String, String)], metrics: &[Metric]) -> Value {
+    let metrics: Vec<Value> = to_gauge_families(metrics).iter()
+        .filter_map(|family| {
+            // JSON has no NaN or infinity, so like the line protocol those
+            // values are left out
+            let data_points: Vec<Value> = family.samples.iter()
+                .filter_map(|sample| {
+                    let mut point = json!({
+                        "attributes": to_attributes(&sample.labels),
+                        "timeUnixNano": sample.timestamp.to_string(),
+                    });
+                    match sample.value {
+                        GaugeValue::Int(i) => point["asInt"] = Value::from(i.to_string()),
+                        GaugeValue::Double(f) if f.is_finite() => point["asDouble"] = Value::from(f),
+                        GaugeValue::Double(_) => return None,
+                    }
+                    Some(point)
+                })
+                .collect();
+            if data_points.is_empty() {
+                return None;
+            }
+
+            Some(json!({
+                "name": family.name,
+                "description": get_help(&family.name),
+                "gauge": { "dataPoints": data_points },
+            }))
+        })
+        .collect();
+
+    json!({
+        "resource": { "attributes": to_attributes(attributes) },
+        "scopeMetrics": [{
+            "scope": {
+                "name": env!("CARGO_PKG_NAME"),
+                "version": env!("CARGO_PKG_VERSION"),
+            },
+            "metrics": metrics,
+        }],
+    })
+}
+
+impl Exporter for OtlpExporter {
+    fn name(&self) -> &'static str {
+        "otlp"
+    }
+
+    fn export(&mut self, collection: &Collection) -> Result<(), Box<dyn Error>> {
+        let mut request = self.agent.post(&self.url);
+        for (key, value) in &self.headers {
+            request = request.set(key, value);
+        }
+
+        request.send_json(self.to_request(collection))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(key: &str, value: &str) -> (String, String) {
+        (key.to_string(), value.to_string())
+    }
+
+    fn get_exporter(names: &[&str]) -> OtlpExporter {
+        let clusters = names.iter()
+            .map(|name| ClusterResource {
+                name: Some(name.to_string()),
+                attributes: vec![pair("tableau.cluster", name)],
+            })
+            .collect();
+        OtlpExporter::new(&ureq::agent(), "http://localhost:4318/", Vec::new(),
+                          vec![pair("service.name", "tme")], clusters)
+    }
+
+    #[test]
+    fn every_cluster_is_a_resource() {
+        let mut collection = Collection::new();
+        for name in &["prod", "test"] {
+            let mut cluster = Collection::new();
+            cluster.push(Metric::new("tableau_process").tag("node", "node1").field("status_code", 0i64));
+            collection.merge(Some(name), cluster);
+        }
+
+        let request = get_exporter(&["prod", "test", "idle"]).to_request(&collection);
+        let resources = request["resourceMetrics"].as_array().unwrap();
+        // a cluster without metrics has no resource
+        assert_eq!(resources.len(), 2);
+
+        assert_eq!(resources[1]["resource"]["attributes"], json!([
+            { "key": "tableau.cluster", "value": { "stringValue": "test" } },
+            { "key": "service.name", "value": { "stringValue": "tme" } },
+        ]));
+        let metrics = resources[1]["scopeMetrics"][0]["metrics"].as_array().unwrap();
+        assert_eq!(metrics.len(), 1);
+        assert_eq!(metrics[0]["gauge"]["dataPoints"][0]["attributes"], json!([
+            { "key": "node", "value": { "stringValue": "node1" } },
+        ]));
+    }
+
+    fn get_single_exporter() -> OtlpExporter {
+        OtlpExporter::new(&ureq::agent(), "http://localhost:4318/", Vec::new(),
+                          vec![pair("service.name", "tme")],
+                          vec![ClusterResource { name: None, attributes: vec![pair("tableau.tsm.url", "https://tsm:8850/")] }])
+    }
+
+    #[test]
+    fn request_has_gauges_with_typed_values() {
+        let mut collection = Collection::new();
+        let mut metric = Metric::new("tableau_tsm_status")
+            .tag("node", "node1")
+            .field("status_code", 0i64)
+            .field("status", "Running")
+            .field("seconds_in_state", 12.5);
+        metric.timestamp = 1_600_000_000_000_000_000;
+        collection.push(metric);
+
+        let request = get_single_exporter().to_request(&collection);
+        let resource = &request["resourceMetrics"][0];
+        assert_eq!(resource["resource"]["attributes"], json!([
+            { "key": "tableau.tsm.url", "value": { "stringValue": "https://tsm:8850/" } },
+            { "key": "service.name", "value": { "stringValue": "tme" } },
+        ]));
+        assert_eq!(resource["scopeMetrics"][0]["scope"]["name"], env!("CARGO_PKG_NAME"));
+
+        let metrics = &resource["scopeMetrics"][0]["metrics"];
+        assert_eq!(metrics[0], json!({
+            "name": "tableau_tsm_status",
+            "description": get_help("tableau_tsm_status"),
+            "gauge": { "dataPoints": [{
+                "attributes": [
+                    { "key": "node", "value": { "stringValue": "node1" } },
+                    { "key": "status", "value": { "stringValue": "Running" } },
+                ],
+                "timeUnixNano": "1600000000000000000",
+                "asInt": "0",
+            }] },
+        }));
+        assert_eq!(metrics[1]["name"], "tableau_tsm_status_seconds_in_state");
+        assert_eq!(metrics[1]["gauge"]["dataPoints"][0]["asDouble"], 12.5);
+    }
+
+    #[test]
+    fn non_finite_values_are_skipped() {
+        let mut collection = Collection::new();
+        collection.push(Metric::new("tableau_backgrounder")
+            .tag("job_type", "refresh_extracts")
+            .field("pending", 1i64)
+            .field("queue_wait_p95", f64::NAN)
+            .field("ratio", f64::INFINITY));
+        collection.push(Metric::new("tableau_backgrounder")
+            .tag("job_type", "run_flow")
+            .field("queue_wait_p95", 2.0));
+
+        let request = get_single_exporter().to_request(&collection);
+        let metrics = request["resourceMetrics"][0]["scopeMetrics"][0]["metrics"].as_array().unwrap();
+        let names: Vec<&str> = metrics.iter().map(|m| m["name"].as_str().unwrap()).collect();
+        assert_eq!(names, vec!["tableau_backgrounder_pending", "tableau_backgrounder_queue_wait_p95"]);
+
+        let points = metrics[1]["gauge"]["dataPoints"].as_array().unwrap();
+        assert_eq!(points.len(), 1);
+        assert_eq!(points[0]["asDouble"], 2.0);
+        assert!(!request.to_string().contains("null"));
+    }
+}
@@ -0,0 +1,147 @@
+//! Detection of configuration changes waiting for `tsm pending-changes apply`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::error::CheckError;
+use crate::metric::{get_epoch_millis, Collection, Metric};
+use crate::tsm::TsmClient;
+
+/// Remembers when each pending key was first seen, per cluster. TSM does not
+/// report when a change was made, so the pending time counts from the first
+/// poll that saw it.
+#[derive(Default)]
+pub struct PendingChangesTracker {
+    first_seen: Mutex<HashMap<String, HashMap<String, u64>>>,
+}
+
+impl PendingChangesTracker {
+    /// Updates the pending keys of the cluster and returns the time each one
+    /// was first seen, in epoch milliseconds.
+    fn update(&self, cluster: &str, keys: &[String], now: u64) -> Vec<(String, u64)> {
+        let mut first_seen = self.first_seen.lock().unwrap_or_else(|e| e.into_inner());
+        let cluster_keys = first_seen.entry(cluster.to_string()).or_default();
+
+        cluster_keys.retain(|key, _| keys.contains(key));
+        keys.iter()
+            .map(|key| (key.clone(), *cluster_keys.entry(key.clone()).or_insert(now)))
+            .collect()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PendingChangesResponse {
+    pending_changes: PendingChanges,
+}
+
+/// A response without the list of configuration keys fails to parse, so it
+/// is reported as an error rather than as no pending changes.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PendingChanges {
+    config_keys: BTreeMap<String, Value>,
+    #[serde(default)]
+    has_pending_topology_changes: bool,
+}
+
+/// Returns the pending keys. Configuration changes are listed by key,
+/// topology changes are reported as a single `topology` key.
+fn get_pending_keys(response: &PendingChangesResponse) -> Vec<String> {
+    let changes = &response.pending_changes;
+    let mut keys: Vec<String> = changes.config_keys.keys().cloned().collect();
+    if changes.has_pending_topology_changes {
+        keys.push("topology".to_string());
+    }
+    keys
+}
+
+pub fn check_pending_changes(tsm: &TsmClient, tracker: &PendingChangesTracker, cluster: &str,
+                             collection: &mut Collection) -> Result<(), CheckError> {
+    let response: PendingChangesResponse = tsm.get_json("pendingChanges")?;
+    let now = get_epoch_millis();
+
+    let keys = get_pending_keys(&response);
+    let first_seen = tracker.update(cluster, &keys, now);
+
+    for (key, seen_at) in &first_seen {
+        collection.push(Metric::new("tableau_tsm_pending_changes")
+            .tag("key", key)
+            .field("pending_seconds", now.saturating_sub(*seen_at) / 1000));
+    }
+
+    let oldest = first_seen.iter().map(|(_, seen_at)| *seen_at).min().unwrap_or(now);
+    let (status_code, status) = if keys.is_empty() { (0i64, "None") } else { (1i64, "Pending") };
+
+    collection.push(Metric::new("tableau_tsm_pending_changes")
+        .tag("key", "all")
+        .field("status_code", status_code)
+        .field("status", status)
+        .field("pending_keys", keys.len() as i64)
+        .field("pending_seconds", now.saturating_sub(oldest) / 1000));
+
+    collection.set_detail("pending_changes", serde_json::json!({
+        "keys": first_seen.iter()
+            .map(|(key, seen_at)| serde_json::json!({ "key": key, "firstSeen": seen_at }))
+            .collect::<Vec<_>>(),
+    }));
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `GET api/0.5/pendingChanges` with two pending configuration keys.
+    const PENDING_CHANGES_0_5: &str = r#"{
+      "pendingChanges": {
+        "configKeys": {
+          "gateway.timeout": "1800",
+          "wgserver.session.idle_limit": "240"
+        },
+        "hasPendingTopologyChanges": true
+      }
+    }"#;
+
+    fn parse(response: &str) -> Result<Vec<String>, CheckError> {
+        let response: PendingChangesResponse = serde_json::from_str(response)?;
+        Ok(get_pending_keys(&response))
+    }
+
+    #[test]
+    fn pending_keys_are_listed() {
+        assert_eq!(parse(PENDING_CHANGES_0_5).unwrap(),
+                   vec!["gateway.timeout", "wgserver.session.idle_limit", "topology"]);
+    }
+
+    #[test]
+    fn no_pending_changes() {
+        assert!(parse(r#"{ "pendingChanges": { "configKeys": {} } }"#).unwrap().is_empty());
+    }
+
+    #[test]
+    fn missing_list_is_an_error() {
+        assert!(matches!(parse(r#"{ "pendingChanges": {} }"#), Err(CheckError::Parse(_))));
+        assert!(matches!(parse("{}"), Err(CheckError::Parse(_))));
+    }
+
+    #[test]
+    fn pending_time_counts_from_the_first_poll() {
+        let tracker = PendingChangesTracker::default();
+        let keys = |names: &[&str]| names.iter().map(|name| name.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(tracker.update("c", &keys(&["a"]), 1000), vec![("a".to_string(), 1000)]);
+        assert_eq!(tracker.update("c", &keys(&["a", "b"]), 2000),
+                   vec![("a".to_string(), 1000), ("b".to_string(), 2000)]);
+        assert_eq!(tracker.update("other", &keys(&["a"]), 2500), vec![("a".to_string(), 2500)]);
+
+        // an applied key starts over when it is pending again
+        assert_eq!(tracker.update("c", &keys(&["b"]), 3000), vec![("b".to_string(), 2000)]);
+        assert_eq!(tracker.update("c", &keys(&["a", "b"]), 4000),
+                   vec![("a".to_string(), 4000), ("b".to_string(), 2000)]);
+    }
+}
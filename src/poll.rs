@@ -0,0 +1,26 @@
+//! Time of the previous successful poll, for checks that report what
+//! happened since then.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Remembers when a check last succeeded, per cluster.
+#[derive(Default)]
+pub struct PollTracker {
+    last: Mutex<HashMap<String, u64>>,
+}
+
+impl PollTracker {
+    /// Epoch milliseconds of the previous successful poll, `None` before
+    /// the first one.
+    pub fn get(&self, cluster: &str) -> Option<u64> {
+        let last = self.last.lock().unwrap_or_else(|e| e.into_inner());
+        last.get(cluster).copied()
+    }
+
+    /// Records a successful poll that covered everything up to `now`.
+    pub fn set(&self, cluster: &str, now: u64) {
+        let mut last = self.last.lock().unwrap_or_else(|e| e.into_inner());
+        last.insert(cluster.to_string(), now);
+    }
+}
@@ -0,0 +1,90 @@
+use std::io::Write;
+
+use crate::gauge::{get_help, to_gauge_families, GaugeValue};
+use crate::metric::Metric;
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+pub fn write_metrics(out: &mut dyn Write, metrics: &[Metric]) -> std::io::Result<()> {
+    for family in to_gauge_families(metrics) {
+        writeln!(out, "# HELP {} {}", family.name, get_help(&family.name))?;
+        writeln!(out, "# TYPE {} gauge", family.name)?;
+
+        for sample in family.samples {
+            let labels: Vec<String> = sample.labels.iter()
+                .map(|(k, v)| std::format!("{}=\"{}\"", k, escape_label_value(v)))
+                .collect();
+            let value = match sample.value {
+                GaugeValue::Int(i) => i.to_string(),
+                GaugeValue::Double(f) if f == f64::INFINITY => "+Inf".to_string(),
+                GaugeValue::Double(f) if f == f64::NEG_INFINITY => "-Inf".to_string(),
+                GaugeValue::Double(f) => f.to_string(),
+            };
+            if labels.is_empty() {
+                writeln!(out, "{} {}", family.name, value)?;
+            } else {
+                writeln!(out, "{}{{{}}} {}", family.name, labels.join(","), value)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_text(metrics: &[Metric]) -> String {
+        let mut out = Vec::new();
+        write_metrics(&mut out, metrics).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn families_are_written_with_help_and_type() {
+        let metrics = vec![
+            Metric::new("tableau_tsm_status")
+                .tag("node", "node1")
+                .field("status_code", 0i64)
+                .field("status", "Running"),
+            Metric::new("tableau_tsm_status")
+                .tag("node", "node2")
+                .field("status_code", 2i64)
+                .field("status", "Error"),
+        ];
+
+        assert_eq!(to_text(&metrics), "\
+# HELP tableau_tsm_status TSM status code (0 running, 1 busy or passive, 2 error, 3 unavailable, -1 disabled)
+# TYPE tableau_tsm_status gauge
+tableau_tsm_status{node=\"node1\",status=\"Running\"} 0
+tableau_tsm_status{node=\"node2\",status=\"Error\"} 2
+");
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        let metrics = vec![Metric::new("tableau_site")
+            .tag("site", "C:\\ \"quoted\"\nnext")
+            .field("status_code", 0i64)];
+
+        assert!(to_text(&metrics).contains("tableau_site{site=\"C:\\\\ \\\"quoted\\\"\\nnext\"} 0\n"));
+    }
+
+    #[test]
+    fn samples_without_labels_have_no_braces() {
+        let metrics = vec![Metric::new("tableau_systeminfo")
+            .field("elapsed", 1500i64)
+            .field("ratio", f64::INFINITY)];
+        let text = to_text(&metrics);
+
+        assert!(text.contains("\ntableau_systeminfo_elapsed 1500\n"));
+        assert!(text.contains("\ntableau_systeminfo_ratio +Inf\n"));
+        assert!(!text.contains("{}"));
+    }
+}
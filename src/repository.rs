@@ -0,0 +1,403 @@
+//! Checks reading the Tableau repository (the `workgroup` PostgreSQL
+//! database) with the readonly user.
+//!
+//! Like the API sessions, the connection is kept between collections and
+//! reopened when the server closes it. The repository stores timestamps in
+//! UTC without a time zone, and the interval since the previous poll is
+//! measured on the clock of the database, so a skew between the hosts does
+//! not drop or repeat requests.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use postgres::{Client, NoTls};
+
+use crate::cluster::Cluster;
+use crate::error::CheckError;
+use crate::metric::{Collection, Metric};
+use crate::poll::PollTracker;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Keeps a slow query on a large `http_requests` table from stalling the
+/// collection.
+const STATEMENT_TIMEOUT_MILLIS: u64 = 10_000;
+
+const NOW_UTC: &str = "(now() AT TIME ZONE 'UTC')";
+
+/// The repository connection of one cluster.
+#[derive(Default)]
+pub struct RepositorySession {
+    client: Mutex<Option<Client>>,
+}
+
+fn connect(cluster: &Cluster) -> Result<Client, CheckError> {
+    let host = cluster.repository_host.as_deref().ok_or("repository host must be defined")?;
+
+    let mut config = postgres::Config::new();
+    config.host(host)
+        .port(cluster.repository_port)
+        .dbname(&cluster.repository_database)
+        .user(&cluster.repository_user)
+        .application_name("tableau-monitoring-execd")
+        .connect_timeout(CONNECT_TIMEOUT)
+        .options(&std::format!("-c statement_timeout={} -c default_transaction_read_only=on",
+                               STATEMENT_TIMEOUT_MILLIS));
+    if let Some(password) = cluster.get_repository_password() {
+        config.password(password);
+    }
+
+    Ok(config.connect(NoTls)?)
+}
+
+impl RepositorySession {
+    /// Runs the queries of a check on the connection of an earlier
+    /// collection, or on a new one. A connection that was closed in the
+    /// meantime is reopened and the queries are retried once.
+    pub fn query<T>(&self, cluster: &Cluster,
+                    mut queries: impl FnMut(&mut Client) -> Result<T, CheckError>) -> Result<T, CheckError> {
+        let mut client = self.client.lock().unwrap_or_else(|e| e.into_inner());
+
+        let reused = client.as_ref().is_some_and(|client| !client.is_closed());
+        if !reused {
+            *client = Some(connect(cluster)?);
+        }
+
+        let result = match queries(client.as_mut().expect("connected")) {
+            Err(_) if reused && client.as_ref().is_some_and(Client::is_closed) => {
+                *client = Some(connect(cluster)?);
+                queries(client.as_mut().expect("connected"))
+            }
+            result => result,
+        };
+
+        if result.is_err() && client.as_ref().is_some_and(Client::is_closed) {
+            *client = None;
+        }
+        result
+    }
+
+    /// Closes the connection, if there is one.
+    pub fn close(&self) -> Result<(), CheckError> {
+        let mut client = self.client.lock().unwrap_or_else(|e| e.into_inner());
+        match client.take() {
+            Some(client) => Ok(client.close()?),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Current time of the database in epoch milliseconds.
+pub fn get_database_now(client: &mut Client) -> Result<u64, CheckError> {
+    let now: i64 = client.query_one("SELECT (extract(epoch FROM now()) * 1000)::int8", &[])?.get(0);
+    Ok(now as u64)
+}
+
+/// Condition selecting the rows with a UTC `column` after the epoch
+/// milliseconds `$1` and up to `$2`.
+pub fn between_polls(column: &str) -> String {
+    std::format!("{column} > to_timestamp($1::int8 / 1000.0) AT TIME ZONE 'UTC'
+                  AND {column} <= to_timestamp($2::int8 / 1000.0) AT TIME ZONE 'UTC'", column = column)
+}
+
+#[derive(Default)]
+struct JobCounts {
+    queued: i64,
+    running: i64,
+    long_running: i64,
+    /// Seconds the oldest queued job has been waiting.
+    oldest_queued_age: Option<i64>,
+}
+
+impl JobCounts {
+    fn to_metric(&self, job_type: &str) -> Metric {
+        let metric = Metric::new("tableau_repository_jobs")
+            .tag("job_type", job_type)
+            .field("queued", self.queued)
+            .field("running", self.running)
+            .field("long_running", self.long_running);
+        match self.oldest_queued_age {
+            Some(age) => metric.field("oldest_queued_age", age),
+            None => metric,
+        }
+    }
+}
+
+/// Queued and running background jobs by job type.
+fn get_jobs(client: &mut Client, long_running_threshold: Duration) -> Result<Vec<(String, JobCounts)>, CheckError> {
+    let query = std::format!(
+        "SELECT coalesce(job_type, 'unknown'),
+                count(*) FILTER (WHERE started_at IS NULL),
+                count(*) FILTER (WHERE started_at IS NOT NULL),
+                count(*) FILTER (WHERE started_at < {now} - make_interval(secs => $1)),
+                extract(epoch FROM {now} - min(created_at) FILTER (WHERE started_at IS NULL))::int8
+         FROM background_jobs
+         WHERE completed_at IS NULL
+         GROUP BY 1
+         ORDER BY 1", now = NOW_UTC);
+
+    Ok(client.query(query.as_str(), &[&long_running_threshold.as_secs_f64()])?
+        .iter()
+        .map(|row| (row.get(0), JobCounts {
+            queued: row.get(1),
+            running: row.get(2),
+            long_running: row.get(3),
+            oldest_queued_age: row.get(4),
+        }))
+        .collect())
+}
+
+struct Usage {
+    /// Epoch milliseconds on the clock of the database.
+    now: u64,
+    /// Requests and distinct users since the previous poll.
+    http_requests: Option<(i64, i64)>,
+    sessions: i64,
+    database_size: i64,
+}
+
+fn get_usage(client: &mut Client, since: Option<u64>) -> Result<Usage, CheckError> {
+    let now = get_database_now(client)?;
+    let row = client.query_one(
+        "SELECT (SELECT count(*) FROM sessions), pg_database_size(current_database())", &[])?;
+
+    let http_requests = match since {
+        Some(since) => {
+            let query = std::format!("SELECT count(*), count(DISTINCT user_id) FROM http_requests WHERE {}",
+                                     between_polls("created_at"));
+            let row = client.query_one(query.as_str(), &[&(since as i64), &(now as i64)])?;
+            Some((row.get(0), row.get(1)))
+        }
+        None => None,
+    };
+
+    Ok(Usage { now, http_requests, sessions: row.get(0), database_size: row.get(1) })
+}
+
+struct Snapshot {
+    jobs: Vec<(String, JobCounts)>,
+    usage: Usage,
+    elapsed: Duration,
+}
+
+fn query(client: &mut Client, since: Option<u64>, long_running_threshold: Duration) -> Result<Snapshot, CheckError> {
+    let start = Instant::now();
+    let jobs = get_jobs(client, long_running_threshold)?;
+    let usage = get_usage(client, since)?;
+
+    Ok(Snapshot { jobs, usage, elapsed: start.elapsed() })
+}
+
+fn add_metrics(snapshot: &Snapshot, collection: &mut Collection) {
+    let Snapshot { jobs, usage, elapsed } = snapshot;
+
+    let mut total = JobCounts::default();
+    for (job_type, counts) in jobs {
+        collection.push(counts.to_metric(job_type));
+        total.queued += counts.queued;
+        total.running += counts.running;
+        total.long_running += counts.long_running;
+        total.oldest_queued_age = total.oldest_queued_age.max(counts.oldest_queued_age);
+    }
+    collection.push(total.to_metric("all"));
+
+    let (status_code, status) = if total.long_running > 0 { (1i64, "LongRunningJobs") } else { (0i64, "Available") };
+    let mut metric = Metric::new("tableau_repository")
+        .field("status_code", status_code)
+        .field("status", status)
+        .field("sessions", usage.sessions)
+        .field("database_size", usage.database_size)
+        .field("elapsed", elapsed.as_micros());
+    if let Some((requests, users)) = usage.http_requests {
+        metric = metric.field("http_requests", requests).field("http_users", users);
+    }
+    collection.push(metric);
+
+    collection.set_detail("repository", serde_json::json!({
+        "jobs": jobs.iter()
+            .map(|(job_type, counts)| serde_json::json!({
+                "jobType": job_type,
+                "queued": counts.queued,
+                "running": counts.running,
+                "longRunning": counts.long_running,
+                "oldestQueuedAge": counts.oldest_queued_age,
+            }))
+            .collect::<Vec<_>>(),
+        "httpRequests": usage.http_requests.map(|(requests, _)| requests),
+        "httpUsers": usage.http_requests.map(|(_, users)| users),
+        "sessions": usage.sessions,
+        "databaseSize": usage.database_size,
+    }));
+}
+
+/// Reports the background job queue, the requests since the previous poll,
+/// the number of sessions and the size of the repository.
+pub fn check_repository(session: &RepositorySession, cluster: &Cluster, tracker: &PollTracker,
+                        long_running_threshold: Duration, collection: &mut Collection) -> Result<(), CheckError> {
+    let name = cluster.name.as_deref().unwrap_or("");
+    let since = tracker.get(name);
+
+    let snapshot = session.query(cluster, |client| query(client, since, long_running_threshold))?;
+    add_metrics(&snapshot, collection);
+    tracker.set(name, snapshot.usage.now);
+
+    Ok(())
+}
+
+/// Scratch database of the tests of the repository queries, named by a
+/// connection string such as `host=localhost user=postgres dbname=tme_test`
+/// in `TME_TEST_PG`. The tests replace the repository tables they use, so
+/// never point it to a real repository.
+#[cfg(test)]
+pub(crate) mod test_database {
+    use std::sync::{Mutex, MutexGuard};
+
+    use postgres::config::Host;
+    use postgres::{Client, NoTls};
+
+    use crate::cluster::Cluster;
+
+    /// Minimal subset of the repository schema read by the checks.
+    const SCHEMA: &str = "
+        DROP TABLE IF EXISTS background_jobs, http_requests, sessions, sites, projects, workbooks, datasources;
+        CREATE TABLE background_jobs (
+            id serial PRIMARY KEY, job_type varchar, title varchar, subtitle varchar, site_id integer,
+            finish_code integer, notes text, created_at timestamp, started_at timestamp,
+            completed_at timestamp);
+        CREATE TABLE http_requests (id serial PRIMARY KEY, user_id integer, created_at timestamp);
+        CREATE TABLE sessions (session_id varchar PRIMARY KEY);
+        CREATE TABLE sites (id integer PRIMARY KEY, name varchar);
+        CREATE TABLE projects (id integer PRIMARY KEY, name varchar, site_id integer);
+        CREATE TABLE workbooks (id integer PRIMARY KEY, name varchar, site_id integer, project_id integer);
+        CREATE TABLE datasources (id integer PRIMARY KEY, name varchar, site_id integer, project_id integer);";
+
+    /// The tests share the tables, so they run one at a time.
+    static LOCK: Mutex<()> = Mutex::new(());
+
+    pub struct TestDatabase {
+        /// Connection to set up the rows of a test.
+        pub client: Client,
+        /// Cluster whose repository is the test database.
+        pub cluster: Cluster,
+        _lock: MutexGuard<'static, ()>,
+    }
+
+    /// Opens the test database with empty repository tables.
+    pub fn open() -> TestDatabase {
+        let lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let connection = std::env::var("TME_TEST_PG")
+            .expect("TME_TEST_PG must name a scratch PostgreSQL database");
+        let config: postgres::Config = connection.parse().expect("invalid TME_TEST_PG");
+
+        let mut client = config.connect(NoTls).expect("cannot connect to TME_TEST_PG");
+        client.batch_execute(SCHEMA).expect("cannot create the test schema");
+
+        let host = match config.get_hosts().first() {
+            Some(Host::Tcp(host)) => host.clone(),
+            #[cfg(unix)]
+            Some(Host::Unix(path)) => path.to_string_lossy().into_owned(),
+            None => "localhost".to_string(),
+        };
+        let cluster = serde_json::from_value(serde_json::json!({
+            "name": "test",
+            "repository_host": host,
+            "repository_port": config.get_ports().first().copied().unwrap_or(5432),
+            "repository_database": config.get_dbname().expect("TME_TEST_PG must name a database"),
+            "repository_user": config.get_user().expect("TME_TEST_PG must name a user"),
+            "repository_password": config.get_password().map(|p| String::from_utf8_lossy(p).into_owned()),
+        })).expect("test cluster");
+
+        TestDatabase { client, cluster, _lock: lock }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Epoch milliseconds of the database clock `interval` ago.
+    fn get_millis_ago(client: &mut Client, interval: &str) -> u64 {
+        let query = "SELECT (extract(epoch FROM now() - $1::text::interval) * 1000)::int8";
+        client.query_one(query, &[&interval]).unwrap().get::<_, i64>(0) as u64
+    }
+
+    #[test]
+    #[ignore = "needs a scratch PostgreSQL database in TME_TEST_PG"]
+    fn between_polls_excludes_the_previous_poll() {
+        let mut db = test_database::open();
+        let now = get_database_now(&mut db.client).unwrap();
+        let since = now - 60_000;
+        db.client.execute(
+            "INSERT INTO http_requests (user_id, created_at) VALUES
+                 (1, to_timestamp($1::int8 / 1000.0) AT TIME ZONE 'UTC'),
+                 (2, to_timestamp($1::int8 / 1000.0 + 1) AT TIME ZONE 'UTC'),
+                 (3, to_timestamp($2::int8 / 1000.0) AT TIME ZONE 'UTC'),
+                 (4, to_timestamp($2::int8 / 1000.0 + 1) AT TIME ZONE 'UTC')",
+            &[&(since as i64), &(now as i64)]).unwrap();
+
+        let query = std::format!("SELECT array_agg(user_id ORDER BY user_id) FROM http_requests WHERE {}",
+                                 between_polls("created_at"));
+        let users: Vec<i32> = db.client.query_one(query.as_str(), &[&(since as i64), &(now as i64)]).unwrap().get(0);
+        assert_eq!(users, vec![2, 3]);
+    }
+
+    #[test]
+    #[ignore = "needs a scratch PostgreSQL database in TME_TEST_PG"]
+    fn jobs_are_counted_by_type() {
+        let mut db = test_database::open();
+        db.client.batch_execute(&std::format!(
+            "INSERT INTO background_jobs (job_type, created_at, started_at, completed_at) VALUES
+                 ('RefreshExtracts', {now} - interval '10 minutes', NULL, NULL),
+                 ('RefreshExtracts', {now} - interval '1 minute', NULL, NULL),
+                 ('RefreshExtracts', {now} - interval '3 hours', {now} - interval '3 hours', NULL),
+                 ('RefreshExtracts', {now} - interval '3 hours', {now} - interval '3 hours', {now}),
+                 ('SingleSubscriptionNotify', {now}, {now}, NULL)", now = NOW_UTC)).unwrap();
+
+        let jobs = get_jobs(&mut db.client, Duration::from_secs(2 * 3600)).unwrap();
+        let summary: Vec<_> = jobs.iter()
+            .map(|(job_type, c)| (job_type.as_str(), c.queued, c.running, c.long_running))
+            .collect();
+        assert_eq!(summary, vec![("RefreshExtracts", 2, 1, 1), ("SingleSubscriptionNotify", 0, 1, 0)]);
+
+        let age = jobs[0].1.oldest_queued_age.unwrap();
+        assert!((600..660).contains(&age), "oldest queued age {}", age);
+        assert_eq!(jobs[1].1.oldest_queued_age, None);
+    }
+
+    #[test]
+    #[ignore = "needs a scratch PostgreSQL database in TME_TEST_PG"]
+    fn usage_counts_requests_since_the_previous_poll() {
+        let mut db = test_database::open();
+        db.client.batch_execute(&std::format!(
+            "INSERT INTO sessions VALUES ('a'), ('b');
+             INSERT INTO http_requests (user_id, created_at) VALUES
+                 (1, {now} - interval '2 hours'),
+                 (1, {now} - interval '1 minute'),
+                 (1, {now} - interval '1 minute'),
+                 (2, {now} - interval '1 minute')", now = NOW_UTC)).unwrap();
+
+        let usage = get_usage(&mut db.client, None).unwrap();
+        assert_eq!(usage.sessions, 2);
+        assert!(usage.database_size > 0);
+        assert_eq!(usage.http_requests, None);
+
+        let since = get_millis_ago(&mut db.client, "1 hour");
+        let usage = get_usage(&mut db.client, Some(since)).unwrap();
+        assert_eq!(usage.http_requests, Some((3, 2)));
+    }
+
+    #[test]
+    #[ignore = "needs a scratch PostgreSQL database in TME_TEST_PG"]
+    fn closed_connection_is_reopened() {
+        let mut db = test_database::open();
+        let session = RepositorySession::default();
+        let get_pid = |client: &mut Client| Ok(client.query_one("SELECT pg_backend_pid()", &[])?.get::<_, i32>(0));
+
+        let pid = session.query(&db.cluster, get_pid).unwrap();
+        db.client.execute("SELECT pg_terminate_backend($1)", &[&pid]).unwrap();
+
+        let reconnected = session.query(&db.cluster, get_pid).unwrap();
+        assert_ne!(pid, reconnected);
+        assert_eq!(session.query(&db.cluster, get_pid).unwrap(), reconnected);
+        session.close().unwrap();
+    }
+}
@@ -0,0 +1,384 @@
+//! Tableau Server REST API access with Personal Access Token sign-in.
+//!
+//! Like the TSM session, the REST API token is kept between collections. It
+//! is renewed shortly before its estimated expiration or when the server
+//! rejects it, and it is signed out when the process stops. The API version
+//! is the one the server reports in its unauthenticated `serverinfo`.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use ureq::{Agent, Request};
+
+use crate::cluster::Cluster;
+use crate::error::CheckError;
+use crate::metric::{Collection, Metric};
+
+/// Oldest REST API version with Personal Access Token sign-in, also used
+/// to query `serverinfo`.
+const FALLBACK_API_VERSION: &str = "3.6";
+/// Token lifetime when the sign-in response does not estimate it: the
+/// default session timeout of Tableau Server.
+const DEFAULT_TOKEN_LIFETIME: Duration = Duration::from_secs(240 * 60);
+/// A token is renewed this long before its estimated expiration.
+const EXPIRATION_MARGIN: Duration = Duration::from_secs(60);
+const PAGE_SIZE: usize = 1000;
+
+/// Parses the `HHH:MM:SS` format of `estimatedTimeToExpiration`.
+fn parse_expiration(value: &str) -> Option<Duration> {
+    let parts: Vec<u64> = value.split(':')
+        .map(|part| part.parse().ok())
+        .collect::<Option<_>>()?;
+
+    match parts.as_slice() {
+        [hours, minutes, seconds] => Some(Duration::from_secs(hours * 3600 + minutes * 60 + seconds)),
+        _ => None,
+    }
+}
+
+fn get_str(value: &Value, pointer: &str) -> String {
+    value.pointer(pointer).and_then(Value::as_str).unwrap_or("").to_string()
+}
+
+/// Tableau returns the pagination counters as strings.
+fn get_count(value: &Value, pointer: &str) -> Option<usize> {
+    match value.pointer(pointer)? {
+        Value::String(s) => s.parse().ok(),
+        other => other.as_u64().map(|n| n as usize),
+    }
+}
+
+/// A page is the last one when it is empty, so a wrong `totalAvailable`
+/// cannot make the listing loop forever, or when every item was fetched.
+fn is_last_page(response: &Value, received: usize, fetched: usize) -> bool {
+    let total = get_count(response, "/pagination/totalAvailable").unwrap_or(0);
+    received == 0 || fetched >= total
+}
+
+struct Token {
+    value: String,
+    site_id: String,
+    content_url: String,
+    expires_at: Instant,
+}
+
+impl Token {
+    /// Reads the `credentials` of a sign-in or site switch response.
+    fn from_response(response: &Value) -> Self {
+        let lifetime = response.pointer("/credentials/estimatedTimeToExpiration")
+            .and_then(Value::as_str)
+            .and_then(parse_expiration)
+            .unwrap_or(DEFAULT_TOKEN_LIFETIME);
+
+        Token {
+            value: get_str(response, "/credentials/token"),
+            site_id: get_str(response, "/credentials/site/id"),
+            content_url: get_str(response, "/credentials/site/contentUrl"),
+            expires_at: Instant::now() + lifetime.saturating_sub(EXPIRATION_MARGIN),
+        }
+    }
+}
+
+struct RestState {
+    api_version: String,
+    token: Option<Token>,
+    sign_ins: u64,
+    sign_in_failures: u64,
+    sign_in_latency: Duration,
+}
+
+/// A site of the server.
+pub struct Site {
+    pub id: String,
+    pub name: String,
+    pub content_url: String,
+    pub state: String,
+}
+
+/// The REST API session of one cluster, signed in to the site configured
+/// for the cluster.
+pub struct RestSession {
+    agent: Agent,
+    server_url: String,
+    state: Mutex<RestState>,
+}
+
+impl RestSession {
+    pub fn new(cluster: &Cluster) -> Self {
+        RestSession {
+            agent: crate::build_agent(),
+            server_url: cluster.systeminfo_hostname.clone(),
+            state: Mutex::new(RestState {
+                api_version: FALLBACK_API_VERSION.to_string(),
+                token: None,
+                sign_ins: 0,
+                sign_in_failures: 0,
+                sign_in_latency: Duration::default(),
+            }),
+        }
+    }
+
+    fn get_url(&self, api_version: &str, path: &str) -> String {
+        std::format!("{}api/{}/{}", self.server_url, api_version, path)
+    }
+
+    fn get_api_version(&self) -> Result<String, CheckError> {
+        let response: Value = self.agent.get(&self.get_url(FALLBACK_API_VERSION, "serverinfo"))
+            .set("Accept", "application/json")
+            .call()?
+            .into_json()?;
+
+        Ok(response.pointer("/serverInfo/restApiVersion")
+            .and_then(Value::as_str)
+            .unwrap_or(FALLBACK_API_VERSION)
+            .to_string())
+    }
+
+    fn request_sign_in(&self, cluster: &Cluster, api_version: &str) -> Result<Token, CheckError> {
+        let (name, secret) = match (&cluster.pat_name, cluster.get_pat_secret()?) {
+            (Some(name), Some(secret)) => (name, secret),
+            _ => return Err("Personal Access Token name and secret must be defined".into()),
+        };
+
+        let response: Value = self.agent.post(&self.get_url(api_version, "auth/signin"))
+            .set("Accept", "application/json")
+            .send_json(ureq::json!({
+                "credentials": {
+                    "personalAccessTokenName": name,
+                    "personalAccessTokenSecret": secret,
+                    "site": { "contentUrl": cluster.site },
+                }
+            }))?
+            .into_json()?;
+
+        Ok(Token::from_response(&response))
+    }
+
+    fn sign_in(&self, cluster: &Cluster, state: &mut RestState) -> Result<(), CheckError> {
+        let start = Instant::now();
+        state.token = None;
+
+        let result = self.get_api_version().and_then(|api_version| {
+            let token = self.request_sign_in(cluster, &api_version);
+            state.api_version = api_version;
+            token
+        });
+        state.sign_ins += 1;
+        state.sign_in_latency = start.elapsed();
+
+        match result {
+            Ok(token) => {
+                state.token = Some(token);
+                Ok(())
+            }
+            Err(e) => {
+                state.sign_in_failures += 1;
+                Err(e)
+            }
+        }
+    }
+
+    /// Replaces the token by one of the site with `content_url`, unless the
+    /// token already belongs to that site.
+    fn request_switch_site(&self, state: &mut RestState, content_url: &str) -> Result<(), CheckError> {
+        let token = state.token.as_ref().expect("signed in");
+        if token.content_url == content_url {
+            return Ok(());
+        }
+
+        let response: Value = self.agent.post(&self.get_url(&state.api_version, "auth/switchSite"))
+            .set("Accept", "application/json")
+            .set("X-Tableau-Auth", &token.value)
+            .send_json(ureq::json!({ "site": { "contentUrl": content_url } }))?
+            .into_json()?;
+
+        state.token = Some(Token::from_response(&response));
+        Ok(())
+    }
+
+    fn has_valid_token(state: &RestState) -> bool {
+        state.token.as_ref().is_some_and(|token| token.expires_at > Instant::now())
+    }
+
+    fn get(&self, state: &RestState, path: &str) -> Request {
+        let token = state.token.as_ref().expect("signed in");
+        let path = path.replace("{site_id}", &token.site_id);
+
+        self.agent.get(&self.get_url(&state.api_version, &path))
+            .set("Accept", "application/json")
+            .set("X-Tableau-Auth", &token.value)
+    }
+
+    /// Signs in unless the token of an earlier collection is still valid.
+    pub fn connect<'a>(&'a self, cluster: &'a Cluster) -> Result<RestClient<'a>, CheckError> {
+        let mut state = self.state.lock().expect("REST session lock poisoned");
+        if !Self::has_valid_token(&state) {
+            self.sign_in(cluster, &mut state)?;
+        }
+
+        Ok(RestClient { session: self, cluster })
+    }
+
+    /// GETs `path`, relative to the REST API root. A rejected token is
+    /// replaced by a new sign-in and the request is retried once.
+    fn get_json<T: DeserializeOwned>(&self, cluster: &Cluster, path: &str) -> Result<T, CheckError> {
+        let mut state = self.state.lock().expect("REST session lock poisoned");
+        let reused = Self::has_valid_token(&state);
+
+        if !reused {
+            self.sign_in(cluster, &mut state)?;
+        }
+
+        let response = match self.get(&state, path).call() {
+            Err(ureq::Error::Status(401, _)) if reused => {
+                // a new sign-in is to the configured site, return to the
+                // site the session was switched to
+                let content_url = state.token.as_ref().map(|token| token.content_url.clone()).unwrap_or_default();
+                self.sign_in(cluster, &mut state)?;
+                self.request_switch_site(&mut state, &content_url)?;
+                self.get(&state, path).call()?
+            }
+            result => result?,
+        };
+
+        Ok(response.into_json()?)
+    }
+
+    fn switch_site(&self, cluster: &Cluster, content_url: &str) -> Result<(), CheckError> {
+        let mut state = self.state.lock().expect("REST session lock poisoned");
+        if !Self::has_valid_token(&state) {
+            self.sign_in(cluster, &mut state)?;
+        }
+
+        self.request_switch_site(&mut state, content_url)
+    }
+
+    /// Signs out the token, if there is one.
+    pub fn sign_out(&self) -> Result<(), CheckError> {
+        let mut state = self.state.lock().expect("REST session lock poisoned");
+        let token = match state.token.take() {
+            Some(token) => token,
+            None => return Ok(()),
+        };
+
+        self.agent.post(&self.get_url(&state.api_version, "auth/signout"))
+            .set("X-Tableau-Auth", &token.value)
+            .call()?;
+
+        Ok(())
+    }
+
+    /// Adds the sign-in counters of the session since the start of the process.
+    pub fn add_metrics(&self, collection: &mut Collection) {
+        let state = self.state.lock().expect("REST session lock poisoned");
+
+        collection.push(Metric::new("tableau_rest_session")
+            .field("signed_in", state.token.is_some())
+            .field("api_version", state.api_version.as_str())
+            .field("sign_ins", state.sign_ins)
+            .field("sign_in_failures", state.sign_in_failures)
+            .field("sign_in_latency", state.sign_in_latency.as_micros()));
+    }
+}
+
+/// The REST API session of a cluster as used by the checks of one collection.
+pub struct RestClient<'a> {
+    session: &'a RestSession,
+    cluster: &'a Cluster,
+}
+
+impl<'a> RestClient<'a> {
+    /// GETs `path`, relative to the REST API root, and parses the JSON
+    /// response. `{site_id}` in the path is replaced by the id of the site
+    /// the session is signed in to.
+    pub fn get_json<T: DeserializeOwned>(&self, path: &str) -> Result<T, CheckError> {
+        self.session.get_json(self.cluster, path)
+    }
+
+    /// Switches the session to the site with `content_url`. Only server
+    /// administrators may switch to another site than the configured one.
+    pub fn switch_site(&self, content_url: &str) -> Result<(), CheckError> {
+        self.session.switch_site(self.cluster, content_url)
+    }
+
+    /// GETs every page of a paged list and returns the items found at
+    /// `items` (a JSON pointer, e.g. `/sites/site`) of each page.
+    pub fn get_all_pages(&self, path: &str, items: &str) -> Result<Vec<Value>, CheckError> {
+        let separator = if path.contains('?') { '&' } else { '?' };
+        let mut result = Vec::new();
+
+        for page in 1.. {
+            let response: Value = self.get_json(&std::format!("{}{}pageSize={}&pageNumber={}",
+                                                              path, separator, PAGE_SIZE, page))?;
+            let page_items = match response.pointer(items) {
+                Some(Value::Array(page_items)) => page_items.clone(),
+                _ => Vec::new(),
+            };
+            let received = page_items.len();
+            result.extend(page_items);
+
+            if is_last_page(&response, received, result.len()) {
+                break;
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Lists the sites of the server. Only server administrators see every
+    /// site; other users get the site they are signed in to.
+    pub fn get_sites(&self) -> Result<Vec<Site>, CheckError> {
+        let sites = match self.get_all_pages("sites", "/sites/site") {
+            Err(CheckError::AuthRejected(_)) => {
+                let response: Value = self.get_json("sites/{site_id}")?;
+                response.get("site").cloned().into_iter().collect()
+            }
+            result => result?,
+        };
+
+        Ok(sites.iter()
+            .map(|site| Site {
+                id: get_str(site, "/id"),
+                name: get_str(site, "/name"),
+                content_url: get_str(site, "/contentUrl"),
+                state: get_str(site, "/state"),
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expiration_is_parsed() {
+        assert_eq!(parse_expiration("361:40:10"), Some(Duration::from_secs(361 * 3600 + 40 * 60 + 10)));
+        assert_eq!(parse_expiration("0:00:00"), Some(Duration::from_secs(0)));
+        assert_eq!(parse_expiration("40:10"), None);
+        assert_eq!(parse_expiration("1:2:3:4"), None);
+        assert_eq!(parse_expiration("1:xx:00"), None);
+        assert_eq!(parse_expiration(""), None);
+    }
+
+    #[test]
+    fn paging_stops_at_the_total() {
+        let page = serde_json::json!({ "pagination": { "pageNumber": "2", "pageSize": "100", "totalAvailable": "250" } });
+        assert!(!is_last_page(&page, 100, 200));
+        assert!(is_last_page(&page, 50, 250));
+        assert!(is_last_page(&page, 100, 300));
+
+        let numeric = serde_json::json!({ "pagination": { "totalAvailable": 250 } });
+        assert!(!is_last_page(&numeric, 100, 100));
+    }
+
+    #[test]
+    fn paging_stops_at_an_empty_page() {
+        let page = serde_json::json!({ "pagination": { "totalAvailable": "250" } });
+        assert!(is_last_page(&page, 0, 200));
+        // without a total, one page is all there is
+        assert!(is_last_page(&serde_json::json!({}), 100, 100));
+    }
+}
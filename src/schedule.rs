@@ -0,0 +1,124 @@
+//! Internal timer for the self-scheduled collection mode.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Parses durations like `30s`, `5m`, `1h`, `2d`, `500ms` or a plain number of seconds.
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+
+    let number: u64 = number.parse()
+        .map_err(|_| std::format!("Invalid duration: {}", value))?;
+
+    let seconds = |factor: u64| number.checked_mul(factor)
+        .map(Duration::from_secs)
+        .ok_or_else(|| std::format!("Duration too long: {}", value));
+
+    match unit.trim() {
+        "ms" => Ok(Duration::from_millis(number)),
+        "" | "s" => Ok(Duration::from_secs(number)),
+        "m" => seconds(60),
+        "h" => seconds(3600),
+        "d" => seconds(86400),
+        _ => Err(std::format!("Invalid duration unit in {}, use ms, s, m, h or d", value)),
+    }
+}
+
+fn get_random_below(limit: Duration) -> Duration {
+    let limit = limit.as_millis() as u64;
+    if limit == 0 {
+        return Duration::from_millis(0);
+    }
+
+    // RandomState is seeded randomly per instance, good enough for jitter
+    let random = RandomState::new().build_hasher().finish();
+    Duration::from_millis(random % limit)
+}
+
+/// Time to sleep until the next multiple of `interval` on the wall clock,
+/// plus a random delay of up to `jitter`.
+pub fn get_sleep_until_next_tick(interval: Duration, jitter: Duration) -> Duration {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+
+    get_until_boundary(now, interval) + get_random_below(jitter)
+}
+
+/// Time from `now` (epoch milliseconds) to the next multiple of `interval`,
+/// a full interval when `now` is on a boundary.
+fn get_until_boundary(now: u128, interval: Duration) -> Duration {
+    let interval_ms = interval.as_millis().max(1);
+    Duration::from_millis((interval_ms - now % interval_ms) as u64)
+}
+
+/// Infinite iterator that blocks until the next aligned tick.
+pub struct Ticker {
+    interval: Duration,
+    jitter: Duration,
+}
+
+impl Ticker {
+    pub fn new(interval: Duration, jitter: Duration) -> Self {
+        Ticker { interval, jitter }
+    }
+}
+
+impl Iterator for Ticker {
+    type Item = ();
+
+    fn next(&mut self) -> Option<()> {
+        std::thread::sleep(get_sleep_until_next_tick(self.interval, self.jitter));
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_are_parsed() {
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("30"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration(" 30s "), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration("1h"), Ok(Duration::from_secs(3600)));
+        assert_eq!(parse_duration("2d"), Ok(Duration::from_secs(172_800)));
+    }
+
+    #[test]
+    fn invalid_durations_are_errors() {
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("-5s").is_err());
+        assert_eq!(parse_duration("5w"), Err("Invalid duration unit in 5w, use ms, s, m, h or d".to_string()));
+        assert_eq!(parse_duration("99999999999999999999s"), Err("Invalid duration: 99999999999999999999s".to_string()));
+        assert_eq!(parse_duration("999999999999999999d"), Err("Duration too long: 999999999999999999d".to_string()));
+    }
+
+    #[test]
+    fn ticks_are_aligned_to_the_interval() {
+        let minute = Duration::from_secs(60);
+        // 1_600_000_020_000 is a whole minute
+        assert_eq!(get_until_boundary(1_600_000_040_000, minute), Duration::from_secs(40));
+        assert_eq!(get_until_boundary(1_600_000_079_999, minute), Duration::from_millis(1));
+        assert_eq!(get_until_boundary(1_600_000_020_000, minute), minute);
+        assert_eq!(get_until_boundary(1_600_000_000_123, Duration::from_millis(0)), Duration::from_millis(1));
+    }
+
+    #[test]
+    fn jitter_is_added_to_the_tick() {
+        let interval = Duration::from_secs(10);
+        let jitter = Duration::from_secs(5);
+        for _ in 0..20 {
+            let sleep = get_sleep_until_next_tick(interval, jitter);
+            assert!(sleep > Duration::from_millis(0) && sleep < interval + jitter, "{:?}", sleep);
+        }
+        assert!(get_sleep_until_next_tick(interval, Duration::from_millis(0)) <= interval);
+    }
+}
@@ -0,0 +1,76 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tiny_http::{Header, Request, Response, Server};
+
+use crate::{write_collection, Collector, OutputFormat};
+
+const WORKER_THREADS: usize = 4;
+
+/// Last rendered `/metrics` body, shared between the worker threads. The
+/// mutex is held while collecting, so scrapes arriving during a collection
+/// wait for it and are answered from the fresh cache.
+struct MetricsCache {
+    ttl: Duration,
+    entry: Mutex<Option<(Instant, Vec<u8>)>>,
+}
+
+impl MetricsCache {
+    fn get_or_collect(&self, collector: &Collector) -> Vec<u8> {
+        let mut entry = self.entry.lock().unwrap_or_else(|e| e.into_inner());
+
+        if let Some((collected_at, body)) = entry.as_ref() {
+            if collected_at.elapsed() < self.ttl {
+                return body.clone();
+            }
+        }
+
+        let collection = collector.collect();
+        let mut body = Vec::new();
+        write_collection(&mut body, OutputFormat::Prometheus, &collection)
+            .expect("writing to a Vec cannot fail");
+
+        *entry = Some((Instant::now(), body.clone()));
+        body
+    }
+}
+
+fn handle_request(request: Request, cache: &MetricsCache, collector: &Collector) {
+    let path = request.url().split('?').next().unwrap_or("").to_string();
+
+    let response = match path.as_str() {
+        "/metrics" => {
+            let content_type = Header::from_bytes(&b"Content-Type"[..], &b"text/plain; version=0.0.4"[..])
+                .expect("static header is valid");
+            Response::from_data(cache.get_or_collect(collector))
+                .with_header(content_type)
+        }
+        "/healthz" => Response::from_string("OK"),
+        _ => Response::from_string("Not Found").with_status_code(404),
+    };
+
+    if let Err(e) = request.respond(response) {
+        eprintln!("cannot send response for {}: {}", path, e);
+    }
+}
+
+/// Serves the collected metrics over HTTP. Checks are executed on scrape
+/// of `/metrics`; results are reused for `cache_ttl`.
+pub fn serve(collector: &Collector, listen: &str, cache_ttl: Duration) {
+    let server = Server::http(listen)
+        .unwrap_or_else(|e| {
+            eprintln!("Cannot listen on {}: {}", listen, e);
+            std::process::exit(2);
+        });
+    let cache = MetricsCache { ttl: cache_ttl, entry: Mutex::new(None) };
+
+    std::thread::scope(|scope| {
+        for _ in 0..WORKER_THREADS {
+            scope.spawn(|| {
+                for request in server.incoming_requests() {
+                    handle_request(request, &cache, collector);
+                }
+            });
+        }
+    });
+}
@@ -0,0 +1,36 @@
+//! Site inventory from the REST API.
+
+use crate::error::CheckError;
+use crate::metric::{Collection, Metric};
+use crate::rest::RestClient;
+
+pub fn check_sites(rest: &RestClient, collection: &mut Collection) -> Result<(), CheckError> {
+    let sites = rest.get_sites()?;
+
+    for site in &sites {
+        let (status_code, status) = if site.state == "Active" { (0i64, "Active") } else { (-1i64, "Suspended") };
+        collection.push(Metric::new("tableau_site")
+            .tag("site", &site.name)
+            .field("status_code", status_code)
+            .field("status", status)
+            .field("content_url", site.content_url.as_str()));
+    }
+
+    collection.push(Metric::new("tableau_site")
+        .tag("site", "all")
+        .field("status_code", 0i64)
+        .field("status", "Available")
+        .field("sites", sites.len() as i64)
+        .field("suspended", sites.iter().filter(|s| s.state != "Active").count() as i64));
+
+    collection.set_detail("sites", serde_json::json!(sites.iter()
+        .map(|site| serde_json::json!({
+            "id": site.id,
+            "name": site.name,
+            "contentUrl": site.content_url,
+            "state": site.state,
+        }))
+        .collect::<Vec<_>>()));
+
+    Ok(())
+}
@@ -0,0 +1,149 @@
+//! Delivery outcomes of subscriptions and data-driven alerts from the
+//! background jobs of the repository.
+
+use postgres::Client;
+
+use crate::cluster::Cluster;
+use crate::error::CheckError;
+use crate::metric::{Collection, Metric};
+use crate::poll::PollTracker;
+use crate::repository::{between_polls, get_database_now, RepositorySession};
+
+#[derive(Default)]
+struct Outcomes {
+    failed: i64,
+    /// Failed runs after which Tableau suspended the subscription or alert.
+    suspended: i64,
+    skipped: i64,
+    completed: i64,
+}
+
+impl Outcomes {
+    fn add(&mut self, other: &Outcomes) {
+        self.failed += other.failed;
+        self.suspended += other.suspended;
+        self.skipped += other.skipped;
+        self.completed += other.completed;
+    }
+
+    fn to_metric(&self, site: &str, kind: &str) -> Metric {
+        Metric::new("tableau_subscription")
+            .tag("site", site)
+            .tag("kind", kind)
+            .field("failed", self.failed)
+            .field("suspended", self.suspended)
+            .field("skipped", self.skipped)
+            .field("completed", self.completed)
+    }
+}
+
+/// Outcomes of the subscription and alert jobs completed between the polls,
+/// by site and kind. Job types differ between versions, so they are matched
+/// by name. A failed run that suspended the subscription or alert says so
+/// in its notes; `finish_code` 2 marks runs that were skipped or cancelled,
+/// 0 the ones that were delivered.
+fn get_outcomes(client: &mut Client, since: u64, now: u64) -> Result<Vec<(String, String, Outcomes)>, CheckError> {
+    let query = std::format!(
+        "SELECT coalesce(s.name, 'Unknown'),
+                CASE WHEN bj.job_type ILIKE '%alert%' THEN 'data_alert' ELSE 'subscription' END,
+                count(*) FILTER (WHERE bj.finish_code = 1 AND coalesce(bj.notes, '') NOT ILIKE '%suspend%'),
+                count(*) FILTER (WHERE bj.finish_code = 1 AND bj.notes ILIKE '%suspend%'),
+                count(*) FILTER (WHERE bj.finish_code = 2),
+                count(*) FILTER (WHERE bj.finish_code = 0)
+         FROM background_jobs bj
+         LEFT JOIN sites s ON s.id = bj.site_id
+         WHERE (bj.job_type ILIKE '%subscription%' OR bj.job_type ILIKE '%alert%')
+           AND {}
+         GROUP BY 1, 2
+         ORDER BY 1, 2", between_polls("bj.completed_at"));
+
+    Ok(client.query(query.as_str(), &[&(since as i64), &(now as i64)])?
+        .iter()
+        .map(|row| (row.get(0), row.get(1), Outcomes {
+            failed: row.get(2),
+            suspended: row.get(3),
+            skipped: row.get(4),
+            completed: row.get(5),
+        }))
+        .collect())
+}
+
+/// Reports the failed, suspended and skipped subscription and data-driven
+/// alert runs since the previous poll per site, and a summary whose status
+/// turns to error on a failed or suspended run. The first poll only records
+/// its time.
+pub fn check_subscriptions(session: &RepositorySession, cluster: &Cluster, tracker: &PollTracker,
+                           collection: &mut Collection) -> Result<(), CheckError> {
+    let name = cluster.name.as_deref().unwrap_or("");
+    let since = tracker.get(name);
+
+    let (now, outcomes) = session.query(cluster, |client| {
+        let now = get_database_now(client)?;
+        let outcomes = match since {
+            Some(since) => get_outcomes(client, since, now)?,
+            None => Vec::new(),
+        };
+        Ok((now, outcomes))
+    })?;
+
+    let mut total = Outcomes::default();
+    let mut details = Vec::new();
+    for (site, kind, site_outcomes) in &outcomes {
+        collection.push(site_outcomes.to_metric(site, kind));
+        total.add(site_outcomes);
+
+        details.push(serde_json::json!({
+            "site": site,
+            "kind": kind,
+            "failed": site_outcomes.failed,
+            "suspended": site_outcomes.suspended,
+            "skipped": site_outcomes.skipped,
+            "completed": site_outcomes.completed,
+        }));
+    }
+
+    let (status_code, status) = if total.failed + total.suspended > 0 { (2i64, "Failed") } else { (0i64, "Ok") };
+    collection.push(total.to_metric("all", "all")
+        .field("status_code", status_code)
+        .field("status", status));
+
+    collection.set_detail("subscriptions", serde_json::Value::from(details));
+    tracker.set(name, now);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::test_database;
+
+    #[test]
+    #[ignore = "needs a scratch PostgreSQL database in TME_TEST_PG"]
+    fn outcomes_are_counted_by_site_and_kind() {
+        let mut db = test_database::open();
+        db.client.batch_execute(
+            "INSERT INTO sites VALUES (1, 'Default');
+             INSERT INTO background_jobs (job_type, site_id, finish_code, notes, completed_at) VALUES
+                 ('SingleSubscriptionNotify', 1, 0, NULL, now() AT TIME ZONE 'UTC'),
+                 ('SingleSubscriptionNotify', 1, 0, NULL, now() AT TIME ZONE 'UTC'),
+                 ('SingleSubscriptionNotify', 1, 1, 'Could not render the view', now() AT TIME ZONE 'UTC'),
+                 ('SingleSubscriptionNotify', 1, 1, 'The subscription was suspended', now() AT TIME ZONE 'UTC'),
+                 ('SingleSubscriptionNotify', 1, 2, NULL, now() AT TIME ZONE 'UTC'),
+                 ('SingleSubscriptionNotify', 1, 1, 'old', now() AT TIME ZONE 'UTC' - interval '2 hours'),
+                 ('CheckIfDataAlertConditionIsTrue', 1, 0, NULL, now() AT TIME ZONE 'UTC'),
+                 ('CheckIfDataAlertConditionIsTrue', 2, 1, NULL, now() AT TIME ZONE 'UTC'),
+                 ('RefreshExtracts', 1, 1, NULL, now() AT TIME ZONE 'UTC')").unwrap();
+
+        let now = get_database_now(&mut db.client).unwrap();
+        let outcomes = get_outcomes(&mut db.client, now - 3_600_000, now).unwrap();
+        let summary: Vec<_> = outcomes.iter()
+            .map(|(site, kind, o)| (site.as_str(), kind.as_str(), o.failed, o.suspended, o.skipped, o.completed))
+            .collect();
+        assert_eq!(summary, vec![
+            ("Default", "data_alert", 0, 0, 0, 1),
+            ("Default", "subscription", 1, 1, 1, 2),
+            ("Unknown", "data_alert", 1, 0, 0, 0),
+        ]);
+    }
+}
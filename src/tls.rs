@@ -1,5 +1,3 @@
-use webpki;
-
 pub struct NoCertificateVerification {}
 
 impl rustls::ServerCertVerifier for NoCertificateVerification {
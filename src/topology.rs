@@ -0,0 +1,221 @@
+//! Node hardware and process topology from the active TSM topology.
+
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::error::CheckError;
+use crate::metric::{Collection, Metric};
+use crate::tsm::TsmClient;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Topology {
+    topology_version: TopologyVersion,
+}
+
+/// A topology without a list of nodes fails to parse, so it is reported as
+/// an error rather than as a cluster without nodes.
+#[derive(Deserialize)]
+struct TopologyVersion {
+    nodes: BTreeMap<String, Node>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Node {
+    #[serde(default)]
+    node_info: NodeDetails,
+    #[serde(default)]
+    services: BTreeMap<String, Service>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct NodeDetails {
+    #[serde(default)]
+    host_name: String,
+    #[serde(default)]
+    address: String,
+    processor_count: Option<i64>,
+    total_memory: Option<i64>,
+    file_systems: Option<Vec<FileSystem>>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FileSystem {
+    total_space: Option<i64>,
+    free_space: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct Service {
+    #[serde(default)]
+    instances: Vec<Value>,
+}
+
+struct NodeInfo {
+    node_id: String,
+    hostname: String,
+    address: String,
+    cores: Option<i64>,
+    memory: Option<i64>,
+    disk_total: Option<i64>,
+    disk_free: Option<i64>,
+    processes: Vec<(String, i64)>,
+}
+
+/// Sums the total and free space of the file systems of a node.
+fn get_disk(file_systems: &Option<Vec<FileSystem>>) -> (Option<i64>, Option<i64>) {
+    match file_systems {
+        Some(file_systems) => (
+            file_systems.iter().filter_map(|f| f.total_space).reduce(|a, b| a + b),
+            file_systems.iter().filter_map(|f| f.free_space).reduce(|a, b| a + b),
+        ),
+        None => (None, None),
+    }
+}
+
+fn get_nodes(topology: Topology) -> Vec<NodeInfo> {
+    topology.topology_version.nodes.into_iter()
+        .map(|(node_id, node)| {
+            let (disk_total, disk_free) = get_disk(&node.node_info.file_systems);
+
+            NodeInfo {
+                hostname: node.node_info.host_name,
+                address: node.node_info.address,
+                cores: node.node_info.processor_count,
+                memory: node.node_info.total_memory,
+                disk_total,
+                disk_free,
+                processes: node.services.iter()
+                    .map(|(service, value)| (service.clone(), value.instances.len() as i64))
+                    .collect(),
+                node_id,
+            }
+        })
+        .collect()
+}
+
+fn add_optional(metric: Metric, name: &str, value: Option<i64>) -> Metric {
+    match value {
+        Some(value) => metric.field(name, value),
+        None => metric,
+    }
+}
+
+pub fn check_topology(tsm: &TsmClient, collection: &mut Collection) -> Result<(), CheckError> {
+    let topology: Topology = tsm.get_json("topologies/active?includeNodeInfo=true")?;
+    let nodes = get_nodes(topology);
+
+    for node in &nodes {
+        let mut metric = Metric::new("tableau_node_info")
+            .tag("node", &node.node_id)
+            .tag("hostname", &node.hostname)
+            .tag("address", &node.address)
+            .tag("service", "all")
+            .field("processes", node.processes.iter().map(|(_, count)| count).sum::<i64>());
+        metric = add_optional(metric, "cores", node.cores);
+        metric = add_optional(metric, "memory", node.memory);
+        metric = add_optional(metric, "disk_total", node.disk_total);
+        metric = add_optional(metric, "disk_free", node.disk_free);
+        collection.push(metric);
+
+        for (service, count) in &node.processes {
+            collection.push(Metric::new("tableau_node_info")
+                .tag("node", &node.node_id)
+                .tag("hostname", &node.hostname)
+                .tag("address", &node.address)
+                .tag("service", service)
+                .field("processes", *count));
+        }
+    }
+
+    collection.push(Metric::new("tableau_node_info")
+        .tag("node", "all")
+        .tag("service", "all")
+        .field("status_code", 0i64)
+        .field("status", "Available")
+        .field("nodes", nodes.len() as i64)
+        .field("cores", nodes.iter().filter_map(|n| n.cores).sum::<i64>())
+        .field("memory", nodes.iter().filter_map(|n| n.memory).sum::<i64>()));
+
+    collection.set_detail("topology", serde_json::json!({
+        "nodes": nodes.iter().map(|node| serde_json::json!({
+            "nodeId": node.node_id,
+            "hostname": node.hostname,
+            "address": node.address,
+            "cores": node.cores,
+            "memory": node.memory,
+            "diskTotal": node.disk_total,
+            "diskFree": node.disk_free,
+            "processes": node.processes.iter()
+                .map(|(service, count)| (service.clone(), Value::from(*count)))
+                .collect::<serde_json::Map<String, Value>>(),
+        })).collect::<Vec<_>>(),
+    }));
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `GET api/0.5/topologies/active?includeNodeInfo=true` of a two node cluster.
+    const TOPOLOGY_0_5: &str = r#"{
+      "topologyVersion": {
+        "nodes": {
+          "node1": {
+            "nodeInfo": {
+              "hostName": "tableau-1",
+              "address": "10.0.0.11",
+              "processorCount": 8,
+              "totalMemory": 34359738368,
+              "fileSystems": [
+                { "name": "C:\\", "totalSpace": 107374182400, "freeSpace": 53687091200 },
+                { "name": "D:\\", "totalSpace": 214748364800, "freeSpace": 107374182400 }
+              ]
+            },
+            "services": {
+              "vizqlserver": { "instances": [{ "instanceId": "0" }, { "instanceId": "1" }] },
+              "backgrounder": { "instances": [{ "instanceId": "0" }] }
+            }
+          },
+          "node2": {
+            "nodeInfo": { "hostName": "tableau-2", "address": "10.0.0.12", "processorCount": 4 },
+            "services": {}
+          }
+        },
+        "version": "3"
+      }
+    }"#;
+
+    #[test]
+    fn topology_0_5_is_parsed() {
+        let nodes = get_nodes(serde_json::from_str(TOPOLOGY_0_5).unwrap());
+        assert_eq!(nodes.len(), 2);
+
+        let node = &nodes[0];
+        assert_eq!(node.node_id, "node1");
+        assert_eq!(node.hostname, "tableau-1");
+        assert_eq!(node.address, "10.0.0.11");
+        assert_eq!(node.cores, Some(8));
+        assert_eq!(node.memory, Some(34_359_738_368));
+        assert_eq!(node.disk_total, Some(322_122_547_200));
+        assert_eq!(node.disk_free, Some(161_061_273_600));
+        assert_eq!(node.processes, vec![("backgrounder".to_string(), 1), ("vizqlserver".to_string(), 2)]);
+
+        assert_eq!(nodes[1].memory, None);
+        assert_eq!(nodes[1].disk_total, None);
+        assert!(nodes[1].processes.is_empty());
+    }
+
+    #[test]
+    fn missing_nodes_are_an_error() {
+        let e = serde_json::from_str::<Topology>(r#"{ "topologyVersion": { "version": "3" } }"#).err().unwrap();
+        assert!(matches!(CheckError::from(e), CheckError::Parse(_)));
+    }
+}
@@ -0,0 +1,160 @@
+//! State transition tracking of the TSM service instances across polls.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+/// Node, service and instance id of a service instance.
+pub type InstanceKey = (String, String, String);
+
+struct InstanceState {
+    status: String,
+    /// Start of the current state in epoch milliseconds.
+    since: u64,
+    /// Last status change time reported by TSM.
+    changed_at: u64,
+    transitions: u64,
+}
+
+/// What the tracker knows about an instance after a poll.
+pub struct Observation {
+    /// Previous status and the seconds spent in it, when the status changed
+    /// since the previous poll. A restart between the polls has the same
+    /// previous status.
+    pub previous: Option<(String, u64)>,
+    pub seconds_in_state: u64,
+    /// Number of transitions seen since the start of the process.
+    pub transitions: u64,
+}
+
+/// Remembers the last status of every service instance, per cluster.
+#[derive(Default)]
+pub struct StateTracker {
+    states: Mutex<HashMap<String, HashMap<InstanceKey, InstanceState>>>,
+}
+
+impl StateTracker {
+    /// Records the status of an instance. `changed_at` is the time of the
+    /// last status change reported by TSM in epoch milliseconds, used when
+    /// it is plausible: the tool may start long after the last change, and
+    /// a change may happen well before the next poll. A newer `changed_at`
+    /// with the same status means the instance changed and came back in
+    /// between, which counts as a transition as well.
+    pub fn observe(&self, cluster: &str, key: InstanceKey, status: &str, changed_at: u64, now: u64) -> Observation {
+        let mut states = self.states.lock().unwrap_or_else(|e| e.into_inner());
+        let cluster_states = states.entry(cluster.to_string()).or_default();
+
+        let state = cluster_states.entry(key).or_insert_with(|| InstanceState {
+            status: status.to_string(),
+            since: if changed_at > 0 && changed_at <= now { changed_at } else { now },
+            changed_at,
+            transitions: 0,
+        });
+
+        let mut previous = None;
+        let restarted = changed_at > state.changed_at && changed_at > state.since;
+        if state.status != status || restarted {
+            let since = if changed_at > state.since && changed_at <= now { changed_at } else { now };
+            previous = Some((state.status.clone(), since.saturating_sub(state.since) / 1000));
+            state.status = status.to_string();
+            state.since = since;
+            state.transitions += 1;
+        }
+        state.changed_at = state.changed_at.max(changed_at);
+
+        Observation {
+            previous,
+            seconds_in_state: now.saturating_sub(state.since) / 1000,
+            transitions: state.transitions,
+        }
+    }
+
+    /// Forgets the instances of the cluster that are gone from the topology.
+    pub fn retain(&self, cluster: &str, seen: &HashSet<InstanceKey>) {
+        let mut states = self.states.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(cluster_states) = states.get_mut(cluster) {
+            cluster_states.retain(|key, _| seen.contains(key));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_600_000_000_000;
+
+    fn key(instance: &str) -> InstanceKey {
+        ("node1".to_string(), "backgrounder".to_string(), instance.to_string())
+    }
+
+    #[test]
+    fn first_observation_counts_from_the_reported_change() {
+        let tracker = StateTracker::default();
+
+        let observation = tracker.observe("c", key("0"), "Active", NOW - 60_000, NOW);
+        assert!(observation.previous.is_none());
+        assert_eq!((observation.seconds_in_state, observation.transitions), (60, 0));
+
+        // a change time in the future or missing is not plausible
+        let observation = tracker.observe("c", key("1"), "Active", NOW + 60_000, NOW);
+        assert_eq!(observation.seconds_in_state, 0);
+        let observation = tracker.observe("c", key("2"), "Active", 0, NOW);
+        assert_eq!(observation.seconds_in_state, 0);
+    }
+
+    #[test]
+    fn status_change_is_a_transition() {
+        let tracker = StateTracker::default();
+        tracker.observe("c", key("0"), "Active", NOW - 60_000, NOW);
+
+        let observation = tracker.observe("c", key("0"), "Stopped", NOW + 20_000, NOW + 30_000);
+        assert_eq!(observation.previous, Some(("Active".to_string(), 80)));
+        assert_eq!((observation.seconds_in_state, observation.transitions), (10, 1));
+
+        let observation = tracker.observe("c", key("0"), "Stopped", NOW + 20_000, NOW + 90_000);
+        assert!(observation.previous.is_none());
+        assert_eq!((observation.seconds_in_state, observation.transitions), (70, 1));
+    }
+
+    #[test]
+    fn restart_between_polls_is_a_transition() {
+        let tracker = StateTracker::default();
+        tracker.observe("c", key("0"), "Active", NOW - 60_000, NOW);
+
+        let observation = tracker.observe("c", key("0"), "Active", NOW + 20_000, NOW + 30_000);
+        assert_eq!(observation.previous, Some(("Active".to_string(), 80)));
+        assert_eq!((observation.seconds_in_state, observation.transitions), (10, 1));
+
+        // the same change time on the next poll is no new restart
+        let observation = tracker.observe("c", key("0"), "Active", NOW + 20_000, NOW + 60_000);
+        assert!(observation.previous.is_none());
+        assert_eq!((observation.seconds_in_state, observation.transitions), (40, 1));
+    }
+
+    #[test]
+    fn implausible_change_time_is_no_restart() {
+        let tracker = StateTracker::default();
+        tracker.observe("c", key("0"), "Active", NOW + 60_000, NOW);
+
+        let observation = tracker.observe("c", key("0"), "Active", NOW + 60_000, NOW + 120_000);
+        assert!(observation.previous.is_none());
+        assert_eq!(observation.transitions, 0);
+    }
+
+    #[test]
+    fn retain_forgets_instances_that_are_gone() {
+        let tracker = StateTracker::default();
+        tracker.observe("c", key("0"), "Active", NOW - 60_000, NOW);
+        tracker.observe("c", key("1"), "Active", NOW - 60_000, NOW);
+        tracker.observe("other", key("1"), "Active", NOW - 60_000, NOW);
+        tracker.observe("c", key("1"), "Stopped", NOW, NOW);
+
+        tracker.retain("c", &[key("0")].iter().cloned().collect());
+
+        // a returning instance starts over, the other cluster keeps its state
+        let observation = tracker.observe("c", key("1"), "Active", NOW, NOW);
+        assert_eq!(observation.transitions, 0);
+        assert_eq!(tracker.observe("c", key("0"), "Active", NOW - 60_000, NOW).seconds_in_state, 60);
+        assert_eq!(tracker.observe("other", key("1"), "Active", NOW - 60_000, NOW + 60_000).seconds_in_state, 120);
+    }
+}
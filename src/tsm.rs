@@ -0,0 +1,286 @@
+//! Authenticated access to the TSM API of a cluster.
+//!
+//! The session is kept between collections: the TSM login is only repeated
+//! when the server rejects the session cookie, and the session is logged
+//! out when the process stops.
+//!
+//! The API version is negotiated at every login: the newest version in
+//! `API_VERSIONS` that the server serves is used, 0.5 otherwise. When the
+//! login or the first request on a negotiated version answers 404, the
+//! session falls back to 0.5 for the rest of the process. 0.5 is the only
+//! documented version so far, so nothing is probed yet.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+use ureq::{Agent, Cookie, Request};
+
+use crate::error::CheckError;
+use crate::cluster::Cluster;
+use crate::metric::{Collection, Metric};
+use crate::get_passwordless_result;
+
+/// TSM API versions understood by the checks, newest first. Only add
+/// versions that are documented.
+const API_VERSIONS: &[&str] = &["0.5"];
+const FALLBACK_API_VERSION: &str = "0.5";
+
+fn get_passwordless_cookie(name: Option<String>, value: Option<String>) -> String {
+    match (name,value) {
+        (Some(name),Some(value)) => {
+            Cookie::build(name, value)
+                .domain("localhost")
+                .path("/")
+                .secure(true)
+                .http_only(true)
+                .finish()
+                .to_string()
+        },
+        _ => "".to_string()
+    }
+}
+
+fn is_auth_rejected(e: &ureq::Error) -> bool {
+    matches!(e, ureq::Error::Status(401, _) | ureq::Error::Status(403, _))
+}
+
+/// Deserializes strings, numbers and null into a string, for identifiers
+/// whose JSON type differs between API versions.
+pub fn deserialize_lenient_string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    Ok(match Value::deserialize(deserializer)? {
+        Value::String(s) => s,
+        Value::Null => String::new(),
+        other => other.to_string(),
+    })
+}
+
+/// Deserializes numbers and numeric strings into an integer, 0 otherwise.
+pub fn deserialize_lenient_u64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    Ok(match Value::deserialize(deserializer)? {
+        Value::Number(n) => n.as_u64().or_else(|| n.as_f64().map(|f| f as u64)).unwrap_or(0),
+        Value::String(s) => s.parse().unwrap_or(0),
+        _ => 0,
+    })
+}
+
+struct SessionState {
+    logged_in: bool,
+    api_version: &'static str,
+    /// A request on `api_version` succeeded since the last login.
+    version_confirmed: bool,
+    /// The negotiated version turned out not to work, only 0.5 is used.
+    fallback: bool,
+    /// Session cookie of passwordless logins. Password logins keep the
+    /// session cookie in the agent's cookie store instead.
+    cookie: Option<String>,
+    logins: u64,
+    login_failures: u64,
+    login_latency: Duration,
+}
+
+impl Default for SessionState {
+    fn default() -> Self {
+        SessionState {
+            logged_in: false,
+            api_version: FALLBACK_API_VERSION,
+            version_confirmed: false,
+            fallback: false,
+            cookie: None,
+            logins: 0,
+            login_failures: 0,
+            login_latency: Duration::default(),
+        }
+    }
+}
+
+/// The TSM session of one cluster. It has its own agent, so the session
+/// cookies of different clusters never mix.
+pub struct TsmSession {
+    agent: Agent,
+    tsm_hostname: String,
+    state: Mutex<SessionState>,
+}
+
+impl TsmSession {
+    pub fn new(cluster: &Cluster) -> Self {
+        TsmSession {
+            agent: crate::build_agent(),
+            tsm_hostname: cluster.tsm_hostname.clone(),
+            state: Default::default(),
+        }
+    }
+
+    fn get_url(&self, api_version: &str, path: &str) -> String {
+        std::format!("{}api/{}/{}", self.tsm_hostname, api_version, path)
+    }
+
+    /// Returns the newest API version the server serves. Unauthenticated
+    /// requests to an existing API version are rejected with 401 or 403, or
+    /// answered with JSON. Any other answer, such as 404, a server error or
+    /// the HTML page of a proxy, means the version is not served. The
+    /// fallback version is used without probing.
+    fn negotiate_api_version(&self) -> Result<&'static str, CheckError> {
+        for version in API_VERSIONS.iter().filter(|version| **version != FALLBACK_API_VERSION) {
+            match self.agent.get(&self.get_url(version, "status")).call() {
+                Err(e) if is_auth_rejected(&e) => return Ok(version),
+                Ok(response) if response.content_type() == "application/json" => return Ok(version),
+                Ok(_) | Err(ureq::Error::Status(_, _)) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(FALLBACK_API_VERSION)
+    }
+
+    /// Returns the session cookie of passwordless logins.
+    fn request_login(&self, cluster: &Cluster, api_version: &str) -> Result<Option<String>, CheckError> {
+        if cluster.passwordless {
+            if !cfg!(unix) {
+                return Err("passwordless login is only supported on unix".into());
+            }
+            let login_result = get_passwordless_result(&cluster.tsm_socket)?;
+            return Ok(Some(get_passwordless_cookie(login_result.cookie_name, login_result.cookie_value)));
+        }
+
+        // a misconfigured cluster fails its own checks, not the whole process
+        let name = cluster.tsm_user.as_ref().ok_or("TSM username must be defined")?;
+        let password = cluster.get_tsm_password().ok_or("TSM password must be defined")?;
+
+        self.agent.post(&self.get_url(api_version, "login"))
+            .send_json(ureq::json!({
+            "authentication": {
+                "name": name,
+                "password": password
+            }}))?
+            .into_string()?;
+
+        Ok(None)
+    }
+
+    fn login(&self, cluster: &Cluster, state: &mut SessionState) -> Result<(), CheckError> {
+        let start = Instant::now();
+        state.logged_in = false;
+
+        state.version_confirmed = false;
+
+        let negotiated = if state.fallback { Ok(FALLBACK_API_VERSION) } else { self.negotiate_api_version() };
+        let result = negotiated.and_then(|api_version| {
+            state.api_version = api_version;
+            match self.request_login(cluster, api_version) {
+                Err(CheckError::HttpStatus(404, _)) if api_version != FALLBACK_API_VERSION => {
+                    state.fallback = true;
+                    state.api_version = FALLBACK_API_VERSION;
+                    self.request_login(cluster, FALLBACK_API_VERSION)
+                }
+                result => result,
+            }
+        });
+        state.logins += 1;
+        state.login_latency = start.elapsed();
+
+        match result {
+            Ok(cookie) => {
+                state.cookie = cookie;
+                state.logged_in = true;
+                Ok(())
+            }
+            Err(e) => {
+                state.login_failures += 1;
+                Err(e)
+            }
+        }
+    }
+
+    fn get(&self, state: &SessionState, path: &str) -> Request {
+        let request = self.agent.get(&self.get_url(state.api_version, path));
+
+        match &state.cookie {
+            Some(cookie) => request.set("Cookie", cookie.as_str()),
+            None => request,
+        }
+    }
+
+    /// Logs in unless the session of an earlier collection is still open.
+    pub fn connect<'a>(&'a self, cluster: &'a Cluster) -> Result<TsmClient<'a>, CheckError> {
+        let mut state = self.state.lock().expect("TSM session lock poisoned");
+        if !state.logged_in {
+            self.login(cluster, &mut state)?;
+        }
+
+        Ok(TsmClient { session: self, cluster })
+    }
+
+    /// GETs `path`, relative to the TSM API root. A session rejected by TSM
+    /// is replaced by a new login and the request is retried once.
+    fn get_json<T: DeserializeOwned>(&self, cluster: &Cluster, path: &str) -> Result<T, CheckError> {
+        let mut state = self.state.lock().expect("TSM session lock poisoned");
+        let reused = state.logged_in;
+
+        if !reused {
+            self.login(cluster, &mut state)?;
+        }
+
+        let response = match self.get(&state, path).call() {
+            Err(e) if reused && is_auth_rejected(&e) => {
+                self.login(cluster, &mut state)?;
+                self.get(&state, path).call()?
+            }
+            Err(ureq::Error::Status(404, _))
+                if !state.version_confirmed && state.api_version != FALLBACK_API_VERSION => {
+                state.fallback = true;
+                self.login(cluster, &mut state)?;
+                self.get(&state, path).call()?
+            }
+            result => result?,
+        };
+        state.version_confirmed = true;
+
+        Ok(response.into_json()?)
+    }
+
+    /// Ends the TSM session, if there is one.
+    pub fn logout(&self) -> Result<(), CheckError> {
+        let mut state = self.state.lock().expect("TSM session lock poisoned");
+        if !state.logged_in {
+            return Ok(());
+        }
+        state.logged_in = false;
+
+        let request = self.agent.post(&self.get_url(state.api_version, "logout"));
+        let request = match &state.cookie {
+            Some(cookie) => request.set("Cookie", cookie.as_str()),
+            None => request,
+        };
+        request.call()?;
+
+        Ok(())
+    }
+
+    /// Adds the login counters of the session since the start of the process.
+    pub fn add_metrics(&self, collection: &mut Collection) {
+        let state = self.state.lock().expect("TSM session lock poisoned");
+
+        collection.push(Metric::new("tableau_tsm_session")
+            .field("logged_in", state.logged_in)
+            .field("api_version", state.api_version)
+            .field("logins", state.logins)
+            .field("login_failures", state.login_failures)
+            .field("login_latency", state.login_latency.as_micros()));
+    }
+}
+
+/// The TSM session of a cluster as used by the TSM checks of one collection.
+pub struct TsmClient<'a> {
+    session: &'a TsmSession,
+    cluster: &'a Cluster,
+}
+
+impl<'a> TsmClient<'a> {
+    /// GETs `path`, relative to the TSM API root, and parses the JSON response.
+    pub fn get_json<T: DeserializeOwned>(&self, path: &str) -> Result<T, CheckError> {
+        self.session.get_json(self.cluster, path)
+    }
+}
@@ -0,0 +1,236 @@
+//! Zabbix low-level discovery and sender (trapper) protocol support.
+//!
+//! Items are keyed as `<measurement>.<field>[<tag values>]`, for example
+//! `tableau_tsm_status.status_code[node1,vizqlserver,0]`, matching the item
+//! prototypes `tableau_tsm_status.status_code[{#NODE},{#SERVICE},{#INSTANCE}]`
+//! of the discovered entities.
+
+use std::error::Error;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use serde_json::{json, Map, Value};
+
+use crate::metric::{Collection, FieldValue, Metric};
+use crate::Exporter;
+
+/// Key of the trapper discovery rule the LLD document is sent to.
+pub const DISCOVERY_KEY: &str = "tableau.discovery";
+
+const HEADER: &[u8] = b"ZBXD\x01";
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Tags describing the value rather than the entity, such as the kind of
+/// error of an unavailable check. They are left out of the item keys, so
+/// the key of an entity never changes, and sent as items of their own.
+const VALUE_TAGS: &[&str] = &["error_kind"];
+
+fn is_key_tag(key: &str) -> bool {
+    !VALUE_TAGS.contains(&key)
+}
+
+fn needs_quotes(value: &str) -> bool {
+    value.contains(&[',', ']', '[', '"', ' '][..])
+}
+
+/// The tag value as used in item keys and discovery macros. Zabbix rejects
+/// a quoted parameter ending with a backslash, as it would escape the
+/// closing quote, so trailing backslashes are dropped from those values.
+fn get_parameter(value: &str) -> &str {
+    if needs_quotes(value) {
+        value.trim_end_matches('\\')
+    } else {
+        value
+    }
+}
+
+fn quote_parameter(value: &str) -> String {
+    let value = get_parameter(value);
+    if needs_quotes(value) {
+        std::format!("\"{}\"", value.replace('"', "\\\""))
+    } else {
+        value.to_string()
+    }
+}
+
+pub fn get_item_key(metric: &Metric, field: &str) -> String {
+    let parameters: Vec<String> = metric.tags.iter()
+        .filter(|(k, _)| is_key_tag(k))
+        .map(|(_, v)| quote_parameter(v))
+        .collect();
+
+    std::format!("{}.{}[{}]", metric.measurement, field, parameters.join(","))
+}
+
+/// Builds the LLD document listing every entity that reports a status.
+pub fn get_discovery(collection: &Collection) -> Value {
+    let mut data: Vec<Value> = Vec::new();
+
+    for metric in collection.metrics.iter().filter(|m| m.get_field("status_code").is_some()) {
+        let mut entity = Map::new();
+        entity.insert("{#MEASUREMENT}".to_string(), Value::from(metric.measurement.as_str()));
+        for (key, value) in metric.tags.iter().filter(|(k, _)| is_key_tag(k)) {
+            entity.insert(std::format!("{{#{}}}", key.to_uppercase()), Value::from(get_parameter(value)));
+        }
+
+        let entity = Value::Object(entity);
+        if !data.contains(&entity) {
+            data.push(entity);
+        }
+    }
+
+    json!({ "data": data })
+}
+
+fn to_value(value: &FieldValue) -> String {
+    match value {
+        FieldValue::Integer(i) => i.to_string(),
+        FieldValue::Float(f) => f.to_string(),
+        FieldValue::Str(s) => s.clone(),
+        FieldValue::Boolean(b) => if *b { "1".to_string() } else { "0".to_string() },
+    }
+}
+
+pub struct ZabbixSender {
+    server: String,
+    host: String,
+}
+
+impl ZabbixSender {
+    /// `server` is the `host:port` of the Zabbix server or proxy trapper, `host`
+    /// the name of the Zabbix host the items belong to.
+    pub fn new(server: &str, host: &str) -> Self {
+        let server = if server.contains(':') {
+            server.to_string()
+        } else {
+            std::format!("{}:10051", server)
+        };
+
+        ZabbixSender { server, host: host.to_string() }
+    }
+
+    fn get_sender_data(&self, collection: &Collection) -> Value {
+        let clock = |timestamp: u128| (timestamp / 1_000_000_000) as u64;
+        let ns = |timestamp: u128| (timestamp % 1_000_000_000) as u64;
+
+        let mut data = vec![json!({
+            "host": self.host,
+            "key": DISCOVERY_KEY,
+            "value": get_discovery(collection).to_string(),
+            "clock": clock(collection.timestamp),
+            "ns": ns(collection.timestamp),
+        })];
+
+        for metric in &collection.metrics {
+            let value_tags = metric.tags.iter()
+                .filter(|(k, _)| !is_key_tag(k))
+                .map(|(k, v)| (k, v.clone()));
+            let fields = metric.fields.iter()
+                .map(|(field, value)| (field, to_value(value)));
+
+            for (field, value) in fields.chain(value_tags) {
+                data.push(json!({
+                    "host": self.host,
+                    "key": get_item_key(metric, field),
+                    "value": value,
+                    "clock": clock(metric.timestamp),
+                    "ns": ns(metric.timestamp),
+                }));
+            }
+        }
+
+        json!({ "request": "sender data", "data": data })
+    }
+
+    fn send(&self, request: &Value) -> Result<Value, Box<dyn Error>> {
+        let payload = request.to_string().into_bytes();
+        let mut stream = TcpStream::connect(&self.server)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+
+        stream.write_all(HEADER)?;
+        stream.write_all(&(payload.len() as u64).to_le_bytes())?;
+        stream.write_all(&payload)?;
+
+        let mut header = [0u8; 13];
+        stream.read_exact(&mut header)?;
+        if &header[..5] != HEADER {
+            return Err("invalid Zabbix response header".into());
+        }
+
+        let mut length = [0u8; 4];
+        length.copy_from_slice(&header[5..9]);
+        let mut response = vec![0u8; u32::from_le_bytes(length) as usize];
+        stream.read_exact(&mut response)?;
+
+        Ok(serde_json::from_slice(&response)?)
+    }
+}
+
+impl Exporter for ZabbixSender {
+    fn name(&self) -> &'static str {
+        "zabbix"
+    }
+
+    fn export(&mut self, collection: &Collection) -> Result<(), Box<dyn Error>> {
+        let response = self.send(&self.get_sender_data(collection))?;
+
+        match response["response"].as_str() {
+            Some("success") => Ok(()),
+            _ => Err(std::format!("Zabbix server rejected the data: {}", response).into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(node: &str, service: &str) -> Metric {
+        Metric::new("tableau_tsm_status")
+            .tag("node", node)
+            .tag("service", service)
+            .field("status_code", 0i64)
+    }
+
+    #[test]
+    fn plain_parameters_are_not_quoted() {
+        assert_eq!(get_item_key(&status("node1", "vizqlserver"), "status_code"),
+                   "tableau_tsm_status.status_code[node1,vizqlserver]");
+        assert_eq!(quote_parameter(r"C:\data\"), r"C:\data\");
+    }
+
+    #[test]
+    fn special_parameters_are_quoted() {
+        assert_eq!(quote_parameter("a,b"), r#""a,b""#);
+        assert_eq!(quote_parameter("[x]"), r#""[x]""#);
+        assert_eq!(quote_parameter("Sales Site"), r#""Sales Site""#);
+        assert_eq!(quote_parameter(r#"say "hi""#), r#""say \"hi\"""#);
+        assert_eq!(get_item_key(&status("node1", "a,b").tag("error_kind", "timeout"), "status_code"),
+                   r#"tableau_tsm_status.status_code[node1,"a,b"]"#);
+    }
+
+    #[test]
+    fn quoted_parameters_do_not_end_with_a_backslash() {
+        assert_eq!(quote_parameter(r"D:\Sales Data\"), r#""D:\Sales Data""#);
+        assert_eq!(quote_parameter(r"a,b\\"), r#""a,b""#);
+        assert_eq!(quote_parameter(r"a\b c"), r#""a\b c""#);
+    }
+
+    #[test]
+    fn discovery_lists_every_entity_once() {
+        let mut collection = Collection::new();
+        collection.push(status("node1", "vizqlserver"));
+        collection.push(status("node1", "vizqlserver").tag("error_kind", "timeout"));
+        collection.push(status("node2", r"D:\Sales Data\"));
+        collection.push(Metric::new("tableau_tsm_status").tag("node", "node3").field("status", "Running"));
+
+        assert_eq!(get_discovery(&collection), json!({
+            "data": [
+                { "{#MEASUREMENT}": "tableau_tsm_status", "{#NODE}": "node1", "{#SERVICE}": "vizqlserver" },
+                { "{#MEASUREMENT}": "tableau_tsm_status", "{#NODE}": "node2", "{#SERVICE}": r"D:\Sales Data" },
+            ],
+        }));
+    }
+}